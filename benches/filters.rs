@@ -0,0 +1,29 @@
+//! Filter-chain benchmarks.
+//!
+//! `content | trim | length` over a large message should scale with the
+//! content size, dominated by copying the message into the render context.
+//! Run with `cargo bench --bench filters`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use shimmyjinja::{ChatMessage, RenderContext, Template};
+
+fn trim_length_chain(c: &mut Criterion) {
+    let template = Template::compile(
+        "{% for message in messages %}{{ message.content | trim | length }}{% endfor %}",
+    )
+    .unwrap();
+    let ctx = RenderContext::new();
+
+    let mut group = c.benchmark_group("trim_length");
+    for size in [64 * 1024, 1024 * 1024] {
+        let content = format!("  {}  ", "x".repeat(size));
+        let messages = vec![ChatMessage::new("user", content)];
+        group.bench_with_input(BenchmarkId::from_parameter(size), &messages, |b, msgs| {
+            b.iter(|| template.render(black_box(msgs), &ctx).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, trim_length_chain);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! Variable lookup benchmarks.
+//!
+//! A `tools` list read inside the message loop should cost one clone of the
+//! value reached, not of the whole list, per lookup.
+//! Run with `cargo bench --bench lookups`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use shimmyjinja::{ChatMessage, RenderContext, Template, Value};
+use std::collections::BTreeMap;
+
+fn tool(i: usize) -> Value {
+    let mut function = BTreeMap::new();
+    function.insert("name".to_string(), Value::String(format!("tool_{}", i)));
+    function.insert("description".to_string(), Value::String("x".repeat(256)));
+    let mut tool = BTreeMap::new();
+    tool.insert("function".to_string(), Value::Map(function));
+    Value::Map(tool)
+}
+
+fn tools_in_message_loop(c: &mut Criterion) {
+    let template = Template::compile(
+        "{% for message in messages %}{{ tools[0].function.name }}:{{ message.content }}{% endfor %}",
+    )
+    .unwrap();
+    let messages: Vec<ChatMessage> = (0..64).map(|i| ChatMessage::new("user", format!("m{}", i))).collect();
+
+    let mut group = c.benchmark_group("tools_in_loop");
+    for count in [8, 128] {
+        let mut ctx = RenderContext::new();
+        ctx.set_value("tools", Value::Array((0..count).map(tool).collect()));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &ctx, |b, ctx| {
+            b.iter(|| template.render(black_box(&messages), ctx).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tools_in_message_loop);
+criterion_main!(benches);
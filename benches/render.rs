@@ -0,0 +1,39 @@
+//! End-to-end render benchmarks.
+//!
+//! Renders a Llama 3 style chat template over a 50-message conversation, the
+//! size where output-buffer growth used to show up. Run with
+//! `cargo bench --bench render`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use shimmyjinja::{ChatMessage, RenderContext, Template};
+
+const LLAMA3: &str = concat!(
+    "{{ bos_token }}",
+    "{% for message in messages %}",
+    "{{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>\\n\\n' + message['content'] | trim + '<|eot_id|>' }}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\\n\\n' }}{% endif %}"
+);
+
+fn conversation(turns: usize) -> Vec<ChatMessage> {
+    let mut messages = vec![ChatMessage::new("system", "You are a helpful assistant. Answer concisely.")];
+    for i in 0..turns {
+        let role = if i % 2 == 0 { "user" } else { "assistant" };
+        messages.push(ChatMessage::new(role, format!("Turn {}: {}", i, "lorem ipsum dolor sit amet ".repeat(20))));
+    }
+    messages
+}
+
+fn fifty_message_conversation(c: &mut Criterion) {
+    let template = Template::compile(LLAMA3).unwrap();
+    let messages = conversation(49);
+    let mut ctx = RenderContext::default();
+    ctx.set_var("bos_token", "<|begin_of_text|>");
+
+    c.bench_function("llama3_50_messages", |b| {
+        b.iter(|| template.render(black_box(&messages), &ctx).unwrap())
+    });
+}
+
+criterion_group!(benches, fifty_message_conversation);
+criterion_main!(benches);
@@ -15,11 +15,16 @@ pub enum BinOp {
     NotIn,
 }
 
+/// A call argument: `Some(name)` for `name=value`, `None` for positional.
+pub type Arg = (Option<String>, Expr);
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     StringLit(String),
     IntLit(i64),
+    FloatLit(f64),
     BoolLit(bool),
+    ListLit(Vec<Expr>),                                       // ['a', 'b', c]
     Var(String),
     Attribute(Box<Expr>, String),                             // foo.bar
     Index(Box<Expr>, Box<Expr>),                              // foo['bar'] or foo[0]
@@ -28,8 +33,8 @@ pub enum Expr {
     Not(Box<Expr>),                                           // not expr
     IsTest(Box<Expr>, bool, String),                          // expr is [not] test_name
     Ternary(Box<Expr>, Box<Expr>, Box<Expr>),                 // cond, then_val, else_val
-    Filter(Box<Expr>, String, Vec<Expr>),                     // expr | filter_name(args)
-    Call(String, Vec<Expr>),                                  // func_name(args)
+    Filter(Box<Expr>, String, Vec<Arg>),                      // expr | filter_name(args)
+    Call(String, Vec<Arg>),                                   // func_name(args)
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -37,9 +42,11 @@ pub enum Node {
     Text(String),
     Var(Expr),
     For {
-        target: String,
+        targets: Vec<String>, // `x`, or `k, v` to unpack each item
         iterable: Expr,   // typically Var("messages") but supports any expr
+        filter: Option<Expr>, // `{% for x in xs if cond %}` — applied before iterating, so loop.* counts kept items
         body: Vec<Node>,
+        else_body: Option<Vec<Node>>, // rendered when no items remain after filtering
     },
     If {
         cases: Vec<(Expr, Vec<Node>)>, // (condition, body). Includes if and elifs.
@@ -49,6 +56,111 @@ pub enum Node {
         name: String,
         expr: Expr,
     },
+    SetBlock {
+        name: String,    // {% set name %}...{% endset %} — binds the rendered body
+        body: Vec<Node>,
+    },
+    Generation(Vec<Node>), // {% generation %}...{% endgeneration %} — HF assistant-mask marker
+    FilterBlock {
+        name: String,     // {% filter name(args) %}...{% endfilter %}
+        args: Vec<Arg>,
+        body: Vec<Node>,
+    },
 }
 
 pub type Template = Vec<Node>;
+
+/// Read-only traversal over a parsed template.
+///
+/// The default methods walk every child node and expression. Override
+/// `visit_node` or `visit_expr`, match the variants of interest, and call
+/// [`walk_node`] / [`walk_expr`] to keep descending into children.
+pub trait NodeVisitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visit every top-level node of `template` in source order.
+pub fn walk_template<V: NodeVisitor + ?Sized>(template: &[Node], visitor: &mut V) {
+    for node in template {
+        visitor.visit_node(node);
+    }
+}
+
+/// Visit the expressions and nested bodies of `node`.
+pub fn walk_node<V: NodeVisitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match node {
+        Node::Text(_) => {}
+        Node::Var(e) | Node::Set { expr: e, .. } => visitor.visit_expr(e),
+        Node::For { iterable, filter, body, else_body, .. } => {
+            visitor.visit_expr(iterable);
+            if let Some(f) = filter {
+                visitor.visit_expr(f);
+            }
+            walk_template(body, visitor);
+            if let Some(body) = else_body {
+                walk_template(body, visitor);
+            }
+        }
+        Node::If { cases, else_body } => {
+            for (cond, body) in cases {
+                visitor.visit_expr(cond);
+                walk_template(body, visitor);
+            }
+            if let Some(body) = else_body {
+                walk_template(body, visitor);
+            }
+        }
+        Node::Generation(body) | Node::SetBlock { body, .. } => walk_template(body, visitor),
+        Node::FilterBlock { args, body, .. } => {
+            for (_, a) in args {
+                visitor.visit_expr(a);
+            }
+            walk_template(body, visitor);
+        }
+    }
+}
+
+/// Visit the sub-expressions of `expr`.
+pub fn walk_expr<V: NodeVisitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::StringLit(_) | Expr::IntLit(_) | Expr::FloatLit(_) | Expr::BoolLit(_) | Expr::Var(_) => {}
+        Expr::Attribute(e, _) | Expr::Not(e) | Expr::IsTest(e, _, _) => visitor.visit_expr(e),
+        Expr::Index(a, b) | Expr::BinOp(a, _, b) => {
+            visitor.visit_expr(a);
+            visitor.visit_expr(b);
+        }
+        Expr::Slice(e, start, end) => {
+            visitor.visit_expr(e);
+            for bound in [start, end].into_iter().flatten() {
+                visitor.visit_expr(bound);
+            }
+        }
+        Expr::Ternary(c, t, f) => {
+            visitor.visit_expr(c);
+            visitor.visit_expr(t);
+            visitor.visit_expr(f);
+        }
+        Expr::Filter(e, _, args) => {
+            visitor.visit_expr(e);
+            for (_, a) in args {
+                visitor.visit_expr(a);
+            }
+        }
+        Expr::Call(_, args) => {
+            for (_, a) in args {
+                visitor.visit_expr(a);
+            }
+        }
+        Expr::ListLit(items) => {
+            for item in items {
+                visitor.visit_expr(item);
+            }
+        }
+    }
+}
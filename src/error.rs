@@ -0,0 +1,104 @@
+use std::fmt;
+
+/// A location in the template source.
+///
+/// `offset` is a byte offset; `line` and `column` are 1-based, with the
+/// column counted in characters so multibyte text lines up in editors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Compute the line/column of a byte offset within `source`.
+    pub fn from_offset(source: &str, offset: usize) -> Self {
+        let offset = offset.min(source.len());
+        let before = &source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count() + 1;
+        Self { offset, line, column }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Error returned by the fallible rendering API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    /// The parser found a token other than the one the grammar requires.
+    UnexpectedToken {
+        expected: String,
+        found: String,
+        pos: Position,
+    },
+    /// The template ended while a construct was still open.
+    UnexpectedEof { expected: String, pos: Position },
+    /// A `{#` comment was never closed with `#}`.
+    UnterminatedComment { pos: Position },
+    /// A `{% raw %}` block (opened at `pos`) was never closed with `{% endraw %}`.
+    UnterminatedRaw { pos: Position },
+    /// A string literal's opening quote (at `pos`) was never closed.
+    UnterminatedString { pos: Position },
+    /// An integer literal does not fit in an `i64`.
+    IntOutOfRange { pos: Position },
+    /// A block tag appeared outside the construct it belongs to, such as
+    /// `{% elif %}` after `{% else %}` or with no open `{% if %}`.
+    MisplacedTag { message: String, pos: Position },
+    /// The template parsed but failed while evaluating.
+    Render(String),
+}
+
+impl RenderError {
+    /// Source position of the error, if it was raised while parsing.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            RenderError::UnexpectedToken { pos, .. }
+            | RenderError::UnexpectedEof { pos, .. }
+            | RenderError::UnterminatedComment { pos }
+            | RenderError::UnterminatedRaw { pos }
+            | RenderError::UnterminatedString { pos }
+            | RenderError::IntOutOfRange { pos }
+            | RenderError::MisplacedTag { pos, .. } => Some(*pos),
+            RenderError::Render(_) => None,
+        }
+    }
+
+    /// True for errors raised while tokenizing or parsing the template.
+    pub fn is_parse_error(&self) -> bool {
+        self.position().is_some()
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::UnexpectedToken { expected, found, pos } => {
+                write!(f, "{}: expected {}, found {}", pos, expected, found)
+            }
+            RenderError::UnexpectedEof { expected, pos } => {
+                write!(f, "{}: expected {}, found end of template", pos, expected)
+            }
+            RenderError::UnterminatedComment { pos } => {
+                write!(f, "{}: unterminated comment, expected '#}}'", pos)
+            }
+            RenderError::UnterminatedRaw { pos } => {
+                write!(f, "{}: unterminated raw block, expected '{{% endraw %}}'", pos)
+            }
+            RenderError::UnterminatedString { pos } => {
+                write!(f, "{}: unterminated string literal at byte {}", pos, pos.offset)
+            }
+            RenderError::IntOutOfRange { pos } => write!(f, "{}: integer literal out of range", pos),
+            RenderError::MisplacedTag { message, pos } => write!(f, "{}: {}", pos, message),
+            RenderError::Render(msg) => write!(f, "Render Error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
@@ -1,13 +1,18 @@
 use crate::ast::*;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Value {
     String(String),
     Int(i64),
+    Float(f64),
     Bool(bool),
     Array(Vec<Value>),
-    Map(HashMap<String, Value>),
+    /// Keys are kept sorted, so iteration, `items()` and `tojson` output
+    /// are the same on every run.
+    Map(BTreeMap<String, Value>),
     Null,
 }
 
@@ -16,32 +21,901 @@ impl Value {
         match self {
             Value::Bool(b)   => *b,
             Value::Int(n)    => *n != 0,
+            Value::Float(f)  => *f != 0.0,
             Value::String(s) => !s.is_empty(),
             Value::Array(a)  => !a.is_empty(),
             Value::Map(m)    => !m.is_empty(),
             Value::Null      => false,
         }
     }
+
+    /// Append the value as it appears when interpolated with `{{ ... }}`.
+    pub fn write_output(&self, out: &mut String) {
+        match self {
+            Value::String(s) => out.push_str(s),
+            Value::Int(n)    => out.push_str(&n.to_string()),
+            Value::Float(f)  => out.push_str(&format_float(*f)),
+            Value::Bool(b)   => out.push_str(if *b { "True" } else { "False" }),
+            Value::Null      => {} // Jinja2 renders None/null as empty
+            // Lists and maps (e.g. structured content) render as JSON
+            Value::Array(_) | Value::Map(_) => out.push_str(&self.to_json()),
+        }
+    }
+
+    /// Serialize to a compact JSON string (Python `json.dumps` spacing).
+    ///
+    /// Map keys are emitted in sorted order so output is deterministic.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out, None, 0);
+        out
+    }
+
+    /// Serialize to JSON with `indent` spaces per nesting level, matching
+    /// Python's `json.dumps(value, indent=n)`.
+    pub fn to_json_pretty(&self, indent: usize) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out, Some(indent), 0);
+        out
+    }
+
+    fn write_json(&self, out: &mut String, indent: Option<usize>, depth: usize) {
+        match self {
+            Value::String(s) => write_json_string(out, s),
+            Value::Int(n)    => out.push_str(&n.to_string()),
+            // Python's json.dumps spelling for the non-finite values
+            Value::Float(f) if f.is_nan() => out.push_str("NaN"),
+            Value::Float(f) if f.is_infinite() => out.push_str(if *f > 0.0 { "Infinity" } else { "-Infinity" }),
+            Value::Float(f)  => out.push_str(&format_float(*f)),
+            Value::Bool(b)   => out.push_str(if *b { "true" } else { "false" }),
+            Value::Null      => out.push_str("null"),
+            Value::Array(a) => {
+                out.push('[');
+                for (i, item) in a.iter().enumerate() {
+                    write_json_separator(out, indent, depth + 1, i == 0);
+                    item.write_json(out, indent, depth + 1);
+                }
+                if !a.is_empty() {
+                    write_json_newline(out, indent, depth);
+                }
+                out.push(']');
+            }
+            Value::Map(m) => {
+                out.push('{');
+                for (i, (key, value)) in m.iter().enumerate() {
+                    write_json_separator(out, indent, depth + 1, i == 0);
+                    write_json_string(out, key);
+                    out.push_str(": ");
+                    value.write_json(out, indent, depth + 1);
+                }
+                if !m.is_empty() {
+                    write_json_newline(out, indent, depth);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Numbers compare by value, so `2 == 2.0`; everything else compares
+/// structurally.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(i), Value::Float(f)) | (Value::Float(f), Value::Int(i)) => *i as f64 == *f,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Format a float as Python's `str()` does: the shortest digits that round
+/// trip, with `.0` kept on whole numbers (`2.0`, `0.7`, `1.5`).
+fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        "nan".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else if f.fract() == 0.0 && f.abs() < 1e16 {
+        format!("{:.1}", f)
+    } else {
+        f.to_string()
+    }
+}
+
+/// The numeric value of an `Int` or `Float`, for mixed arithmetic.
+fn as_f64(val: &Value) -> Option<f64> {
+    match val {
+        Value::Int(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// Emit the separator before a container element: `", "` when compact,
+/// `",\n" + indentation` when pretty-printing.
+fn write_json_separator(out: &mut String, indent: Option<usize>, depth: usize, first: bool) {
+    match indent {
+        None => {
+            if !first {
+                out.push_str(", ");
+            }
+        }
+        Some(_) => {
+            if !first {
+                out.push(',');
+            }
+            write_json_newline(out, indent, depth);
+        }
+    }
+}
+
+fn write_json_newline(out: &mut String, indent: Option<usize>, depth: usize) {
+    if let Some(width) = indent {
+        out.push('\n');
+        out.extend(std::iter::repeat_n(' ', width * depth));
+    }
+}
+
+/// Objects become maps, arrays become lists, integral numbers become
+/// `Value::Int` and other numbers (fractions, values beyond `i64`) become
+/// `Value::Float`.
+#[cfg(feature = "serde")]
+impl From<serde_json::Value> for Value {
+    fn from(json: serde_json::Value) -> Self {
+        match json {
+            serde_json::Value::Null      => Value::Null,
+            serde_json::Value::Bool(b)   => Value::Bool(b),
+            serde_json::Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+                (Some(i), _)    => Value::Int(i),
+                (None, Some(f)) => Value::Float(f),
+                (None, None)    => Value::String(n.to_string()),
+            },
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(a)  => Value::Array(a.into_iter().map(Value::from).collect()),
+            serde_json::Value::Object(o) => {
+                Value::Map(o.into_iter().map(|(k, v)| (k, Value::from(v))).collect())
+            }
+        }
+    }
+}
+
+/// Classify an interpolated (non-concatenation) expression for segment output.
+fn expr_origin(expr: &Expr) -> Origin {
+    match expr {
+        Expr::Attribute(_, attr) if attr == "content" => Origin::MessageContent,
+        Expr::Index(_, key) if **key == Expr::StringLit("content".to_string()) => Origin::MessageContent,
+        Expr::Var(name) if name.ends_with("_token") => Origin::SpecialToken,
+        // `message.content | trim` is still message content
+        Expr::Filter(inner, _, _) => expr_origin(inner),
+        _ => Origin::Literal,
+    }
+}
+
+/// True if `expr` reads the variable `name` anywhere.
+fn references_var(expr: &Expr, name: &str) -> bool {
+    any_subexpr(expr, &mut |e| matches!(e, Expr::Var(v) if v == name))
+}
+
+/// Stops descending once `pred` has matched an expression.
+struct AnyExpr<'p> {
+    pred: &'p mut dyn FnMut(&Expr) -> bool,
+    found: bool,
+}
+
+impl NodeVisitor for AnyExpr<'_> {
+    fn visit_node(&mut self, node: &Node) {
+        if !self.found {
+            walk_node(self, node);
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if self.found {
+            return;
+        }
+        if (self.pred)(expr) {
+            self.found = true;
+        } else {
+            walk_expr(self, expr);
+        }
+    }
+}
+
+/// True if `pred` holds for `expr` or any expression nested inside it.
+fn any_subexpr(expr: &Expr, pred: &mut dyn FnMut(&Expr) -> bool) -> bool {
+    let mut visitor = AnyExpr { pred, found: false };
+    visitor.visit_expr(expr);
+    visitor.found
+}
+
+/// True if `pred` holds for any expression in `nodes`, including nested blocks.
+fn nodes_any_expr(nodes: &[Node], pred: &mut dyn FnMut(&Expr) -> bool) -> bool {
+    let mut visitor = AnyExpr { pred, found: false };
+    walk_template(nodes, &mut visitor);
+    visitor.found
+}
+
+/// True if any `{% set %}` or loop target in `nodes` (re)binds `name`.
+fn binds_var(nodes: &[Node], name: &str) -> bool {
+    struct Binds<'n> {
+        name: &'n str,
+        found: bool,
+    }
+
+    impl NodeVisitor for Binds<'_> {
+        fn visit_node(&mut self, node: &Node) {
+            match node {
+                Node::Set { name, .. } | Node::SetBlock { name, .. } if name == self.name => self.found = true,
+                Node::For { targets, .. } if targets.iter().any(|t| t == self.name) => self.found = true,
+                _ => walk_node(self, node),
+            }
+        }
+
+        // Bindings only happen at node level.
+        fn visit_expr(&mut self, _: &Expr) {}
+    }
+
+    let mut visitor = Binds { name, found: false };
+    walk_template(nodes, &mut visitor);
+    visitor.found
+}
+
+/// Names `template` reads from its context: every variable that is not bound
+/// by an enclosing loop (its targets and `loop`) or by an earlier `{% set %}`.
+pub fn variables_used(template: &[Node]) -> HashSet<String> {
+    struct FreeVars {
+        bound: Vec<HashSet<String>>, // one set per open scope, innermost last
+        free: HashSet<String>,
+    }
+
+    impl FreeVars {
+        fn read(&mut self, name: &str) {
+            if !self.bound.iter().any(|scope| scope.contains(name)) {
+                self.free.insert(name.to_string());
+            }
+        }
+
+        fn assign(&mut self, target: &str) {
+            match target.split_once('.') {
+                // `{% set ns.found = ... %}` updates `ns`, so it must exist.
+                Some((base, _)) => self.read(base),
+                None => {
+                    if let Some(scope) = self.bound.last_mut() {
+                        scope.insert(target.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    impl NodeVisitor for FreeVars {
+        fn visit_node(&mut self, node: &Node) {
+            match node {
+                Node::For { targets, iterable, filter, body, else_body } => {
+                    self.visit_expr(iterable);
+                    let mut scope: HashSet<String> = targets.iter().cloned().collect();
+                    scope.insert("loop".to_string());
+                    self.bound.push(scope);
+                    if let Some(filter) = filter {
+                        self.visit_expr(filter);
+                    }
+                    walk_template(body, self);
+                    self.bound.pop();
+                    if let Some(else_body) = else_body {
+                        walk_template(else_body, self);
+                    }
+                }
+                Node::Set { name, expr } => {
+                    self.visit_expr(expr);
+                    self.assign(name);
+                }
+                Node::SetBlock { name, body } => {
+                    walk_template(body, self);
+                    self.assign(name);
+                }
+                _ => walk_node(self, node),
+            }
+        }
+
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let Expr::Var(name) = expr {
+                self.read(name);
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    let mut visitor = FreeVars { bound: vec![HashSet::new()], free: HashSet::new() };
+    walk_template(template, &mut visitor);
+    visitor.free
+}
+
+/// Index of a top-level `{% for x in <var> %}` that can consume `var` as a
+/// one-shot stream, for [`Evaluator::render_streamed`].
+///
+/// The loop must be the template's only reference to `var`, must have no `if`
+/// filter, and its body may only read `loop.index`, `loop.index0`,
+/// `loop.first` and `loop.last` — the fields known without the total length.
+pub fn single_pass_loop(template: &[Node], var: &str) -> Option<usize> {
+    let mut uses = 0;
+    nodes_any_expr(template, &mut |e| {
+        if matches!(e, Expr::Var(v) if v == var) {
+            uses += 1;
+        }
+        false
+    });
+    if uses != 1 || binds_var(template, var) {
+        return None;
+    }
+    let at = template.iter().position(|node| {
+        matches!(node, Node::For { iterable: Expr::Var(v), .. } if v == var)
+    })?;
+    let Node::For { filter: None, body, .. } = &template[at] else {
+        return None;
+    };
+    // Every `loop` reference must be one of the streamable attributes.
+    let (mut loop_refs, mut streamable) = (0, 0);
+    nodes_any_expr(body, &mut |e| {
+        match e {
+            Expr::Var(v) if v == "loop" => loop_refs += 1,
+            Expr::Attribute(inner, attr)
+                if matches!(&**inner, Expr::Var(v) if v == "loop")
+                    && matches!(attr.as_str(), "index" | "index0" | "first" | "last") =>
+            {
+                streamable += 1
+            }
+            _ => {}
+        }
+        false
+    });
+    (loop_refs == streamable).then_some(at)
+}
+
+/// Wrap the result of checked integer arithmetic, reporting overflow as an
+/// error instead of panicking (debug) or wrapping (release).
+fn checked_int(result: Option<i64>, op: &str, a: i64, b: i64) -> Result<Value, String> {
+    result
+        .map(Value::Int)
+        .ok_or_else(|| format!("Integer overflow: {} {} {}", a, op, b))
+}
+
+/// Expand `%s`, `%d` and `%%` in `fmt`, consuming `values` in order.
+fn format_percent(fmt: &str, values: &[Value]) -> Result<String, String> {
+    let mut out = String::with_capacity(fmt.len());
+    let mut next = values.iter();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some('s') => match next.next() {
+                Some(v) => v.write_output(&mut out),
+                None => return Err("not enough arguments for format string".to_string()),
+            },
+            Some('d') => match next.next() {
+                Some(Value::Int(n)) => out.push_str(&n.to_string()),
+                Some(v) => return Err(format!("%d format requires an integer, got {:?}", v)),
+                None => return Err("not enough arguments for format string".to_string()),
+            },
+            Some(other) => return Err(format!("unsupported format specifier '%{}'", other)),
+            None => return Err("incomplete format specifier at end of string".to_string()),
+        }
+    }
+    Ok(out)
+}
+
+/// Remove every `open ... close` span from `s`. An `open` without a matching
+/// `close` is left in place along with everything after it.
+fn strip_spans(s: &str, open: &str, close: &str) -> String {
+    if open.is_empty() || close.is_empty() {
+        return s.to_string();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find(open) {
+        let after_open = &rest[start + open.len()..];
+        match after_open.find(close) {
+            Some(end) => {
+                out.push_str(&rest[..start]);
+                rest = &after_open[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Jinja2's `title`: uppercase the first character of each word and lowercase
+/// the rest. Words begin after whitespace, `-` or an opening bracket.
+fn title_case(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut word_start = true;
+    for c in s.chars() {
+        if word_start {
+            out.extend(c.to_uppercase());
+        } else {
+            out.extend(c.to_lowercase());
+        }
+        word_start = c.is_whitespace() || matches!(c, '-' | '(' | '{' | '[' | '<');
+    }
+    out
+}
+
+/// Jinja2's `truncate`: strings longer than `length + leeway` characters are
+/// cut to `length` including `end`, backing up to the last space unless
+/// `killwords` is set.
+fn truncate(s: &str, length: usize, killwords: bool, end: &str, leeway: usize) -> String {
+    if s.chars().count() <= length.saturating_add(leeway) {
+        return s.to_string();
+    }
+    let kept: String = s.chars().take(length.saturating_sub(end.chars().count())).collect();
+    let kept = match kept.rfind(' ') {
+        Some(i) if !killwords => &kept[..i],
+        _ => kept.as_str(),
+    };
+    format!("{}{}", kept, end)
+}
+
+/// Jinja2's `wordwrap`: wrap every line of `s` to `width` characters at
+/// whitespace (Python `textwrap` rules) and join the pieces with `wrapstring`.
+/// Words longer than `width` are split when `break_long_words` is set.
+fn wordwrap(s: &str, width: usize, break_long_words: bool, wrapstring: &str) -> String {
+    let mut lines = Vec::new();
+    for line in s.lines() {
+        wrap_line(line, width, break_long_words, &mut lines);
+    }
+    lines.join(wrapstring)
+}
+
+fn wrap_line(line: &str, width: usize, break_long_words: bool, out: &mut Vec<String>) {
+    let is_space = |chunk: &str| chunk.chars().all(char::is_whitespace);
+    // Alternating runs of whitespace and non-whitespace, as a stack.
+    let mut chunks: Vec<String> = Vec::new();
+    for c in line.chars() {
+        match chunks.last_mut() {
+            Some(last) if last.starts_with(char::is_whitespace) == c.is_whitespace() => last.push(c),
+            _ => chunks.push(c.to_string()),
+        }
+    }
+    chunks.reverse();
+
+    let mut wrapped = 0;
+    while !chunks.is_empty() {
+        // Whitespace at the start of a continuation line is dropped.
+        if wrapped > 0 && chunks.last().is_some_and(|c| is_space(c)) {
+            chunks.pop();
+        }
+        let mut cur: Vec<String> = Vec::new();
+        let mut cur_len = 0;
+        while let Some(chunk) = chunks.last() {
+            let len = chunk.chars().count();
+            if cur_len + len > width {
+                break;
+            }
+            cur_len += len;
+            cur.extend(chunks.pop());
+        }
+        if let Some(chunk) = chunks.last_mut() {
+            if chunk.chars().count() > width {
+                if break_long_words {
+                    let space_left = width - cur_len;
+                    let split = chunk.char_indices().nth(space_left).map_or(chunk.len(), |(i, _)| i);
+                    let tail = chunk.split_off(split);
+                    cur.push(std::mem::replace(chunk, tail));
+                } else if cur.is_empty() {
+                    cur.extend(chunks.pop());
+                }
+            }
+        }
+        if cur.last().is_some_and(|c| is_space(c)) {
+            cur.pop();
+        }
+        if !cur.is_empty() {
+            out.push(cur.concat());
+            wrapped += 1;
+        }
+    }
+    // A blank line still separates paragraphs in the output.
+    if wrapped == 0 {
+        out.push(String::new());
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Where a piece of rendered output came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Template text or string literals.
+    Literal,
+    /// A message's `content` field.
+    MessageContent,
+    /// A context variable named `*_token` (e.g. `bos_token`, `eos_token`).
+    SpecialToken,
+    /// Anything rendered inside an `{% if add_generation_prompt %}` branch.
+    GenerationPrompt,
+}
+
+/// A run of rendered output tagged with its [`Origin`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputSegment {
+    pub text: String,
+    pub origin: Origin,
+}
+
+/// Facts about a finished render, for logging and telemetry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderInfo {
+    /// True if any output came from an `{% if add_generation_prompt %}`
+    /// branch, i.e. the template actually appended the assistant prompt.
+    pub generation_prompt_emitted: bool,
+    /// Problems that lenient mode rendered past instead of failing, such as
+    /// a `{% for %}` over a value that is not iterable.
+    pub warnings: Vec<String>,
+}
+
+/// The items a `{% for %}` visits: list elements, the characters of a string,
+/// or the keys of a map in sorted order. Anything else is not iterable, which
+/// the `is iterable` test reports the same way.
+fn iter_items(val: Value) -> Result<Vec<Value>, String> {
+    match val {
+        Value::Array(items) => Ok(items),
+        Value::String(s) => Ok(s.chars().map(|c| Value::String(c.to_string())).collect()),
+        Value::Map(map) => Ok(map.into_keys().map(Value::String).collect()),
+        other => Err(format!("Cannot iterate over {:?}", other)),
+    }
+}
+
+/// The argument for parameter `name`, passed either as `name=value` or as the
+/// positional argument at `pos`.
+fn arg<'a>(args: &'a [Arg], pos: usize, name: &str) -> Option<&'a Expr> {
+    args.iter()
+        .find(|(key, _)| key.as_deref() == Some(name))
+        .or_else(|| args.get(pos).filter(|(key, _)| key.is_none()))
+        .map(|(_, value)| value)
+}
+
+/// Format the pinned timestamp (UTC) or the current local time with a
+/// strftime `format`.
+#[cfg(feature = "chrono")]
+fn strftime_now(format: &str, pinned: Option<i64>) -> Result<String, String> {
+    use chrono::format::{Item, StrftimeItems};
+
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return Err(format!("strftime_now(): invalid format '{}'", format));
+    }
+    Ok(match pinned {
+        Some(timestamp) => chrono::DateTime::from_timestamp(timestamp, 0)
+            .ok_or_else(|| format!("strftime_now(): timestamp {} out of range", timestamp))?
+            .format_with_items(items.iter())
+            .to_string(),
+        None => chrono::Local::now().format_with_items(items.iter()).to_string(),
+    })
+}
+
+#[cfg(not(feature = "chrono"))]
+fn strftime_now(_format: &str, _pinned: Option<i64>) -> Result<String, String> {
+    Err("strftime_now() requires the 'chrono' feature".to_string())
+}
+
+/// Parse an integer the way Jinja2's `int` filter does: plain digits with an
+/// optional sign, or a decimal whose fraction is dropped.
+fn parse_int(s: &str) -> Option<i64> {
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(n);
+    }
+    let (whole, fraction) = s.split_once('.')?;
+    if !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match whole {
+        "" | "-" | "+" if !fraction.is_empty() => Some(0),
+        _ => whole.parse().ok(),
+    }
+}
+
+/// Convert a numeric string compared against an integer into that integer,
+/// for [`Evaluator::set_coerce_numeric_strings`]. Other pairs are unchanged.
+fn coerce_numeric(l: Value, r: Value) -> (Value, Value) {
+    match (l, r) {
+        (Value::Int(a), Value::String(s)) => match s.trim().parse() {
+            Ok(b) => (Value::Int(a), Value::Int(b)),
+            Err(_) => (Value::Int(a), Value::String(s)),
+        },
+        (Value::String(s), Value::Int(b)) => match s.trim().parse() {
+            Ok(a) => (Value::Int(a), Value::Int(b)),
+            Err(_) => (Value::String(s), Value::Int(b)),
+        },
+        pair => pair,
+    }
+}
+
+/// Replace the five HTML-special characters with the entities markupsafe uses.
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&#34;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Turn a possibly negative index into a position in a sequence of `len`
+/// items, counting negative indices from the end as Python does.
+fn resolve_index(i: i64, len: usize) -> Result<usize, String> {
+    let resolved = match usize::try_from(i.unsigned_abs()) {
+        Ok(back) if i < 0 => len.checked_sub(back),
+        Ok(idx) => Some(idx),
+        Err(_) => None,
+    };
+    match resolved {
+        Some(idx) if idx < len => Ok(idx),
+        _ => Err(format!("Index {} out of bounds (len={})", i, len)),
+    }
+}
+
+/// Most items `range()` may produce, as in Jinja2's sandbox.
+const MAX_RANGE: usize = 100_000;
+
+/// Upper bound on the buffer `render` reserves up front. The estimate is
+/// only a hint, so a template whose literals look huge must not turn it into
+/// a huge allocation; past this the buffer grows as usual.
+const MAX_CAPACITY_HINT: usize = 4 << 20;
+
+/// Rough output size for `template` when rendered over `messages` messages:
+/// literal text, with the body of the outermost `{% for ... in messages %}`
+/// loop counted once per message and only the largest branch of each `if`.
+/// Message contents are added separately by the caller.
+fn literal_capacity(template: &[Node], messages: usize) -> usize {
+    let branches = |bodies: &mut dyn Iterator<Item = &[Node]>| {
+        bodies.map(|body| literal_capacity(body, messages)).max().unwrap_or(0)
+    };
+    template
+        .iter()
+        .map(|node| match node {
+            Node::Text(s) => s.len(),
+            // Nested loops over `messages` count once, not once per outer message.
+            Node::For { iterable: Expr::Var(v), body, .. } if v == "messages" => {
+                literal_capacity(body, 1).saturating_mul(messages)
+            }
+            Node::For { body, else_body, .. } => {
+                branches(&mut std::iter::once(body.as_slice()).chain(else_body.as_deref()))
+            }
+            Node::If { cases, else_body } => {
+                branches(&mut cases.iter().map(|(_, body)| body.as_slice()).chain(else_body.as_deref()))
+            }
+            Node::Generation(body) | Node::FilterBlock { body, .. } => literal_capacity(body, messages),
+            Node::Var(expr) => {
+                let mut literals = StringLiterals(0);
+                literals.visit_expr(expr);
+                literals.0
+            }
+            Node::Set { .. } | Node::SetBlock { .. } => 0,
+        })
+        .fold(0, usize::saturating_add)
+}
+
+/// Sums the string literals in an output expression, such as the role
+/// headers in `{{ '<|user|>\n' + message['content'] }}`.
+struct StringLiterals(usize);
+
+impl NodeVisitor for StringLiterals {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::StringLit(s) = expr {
+            self.0 += s.len();
+        }
+        walk_expr(self, expr);
+    }
+}
+
+/// The `loop.*` fields that do not depend on the sequence length.
+fn loop_vars(i: usize, last: bool) -> BTreeMap<String, Value> {
+    let mut loop_map = BTreeMap::new();
+    loop_map.insert("index0".to_string(), Value::Int(i as i64));
+    loop_map.insert("index".to_string(),  Value::Int(i as i64 + 1));
+    loop_map.insert("first".to_string(),  Value::Bool(i == 0));
+    loop_map.insert("last".to_string(),   Value::Bool(last));
+    loop_map
+}
+
+/// Destination for rendered text: an in-memory `String` or an `io::Write`.
+trait Output {
+    fn push(&mut self, text: &str) -> Result<(), String>;
+}
+
+impl Output for String {
+    fn push(&mut self, text: &str) -> Result<(), String> {
+        self.push_str(text);
+        Ok(())
+    }
+}
+
+/// Adapts an `io::Write`, keeping the underlying error so
+/// [`Evaluator::render_to`] can return it unchanged.
+struct IoOutput<'w, W: Write> {
+    writer: &'w mut W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Output for IoOutput<'_, W> {
+    fn push(&mut self, text: &str) -> Result<(), String> {
+        self.writer.write_all(text.as_bytes()).map_err(|e| {
+            let msg = format!("Write failed: {}", e);
+            self.error = Some(e);
+            msg
+        })
+    }
 }
 
 pub struct Evaluator {
     scopes: Vec<HashMap<String, Value>>,
+    segments: Option<Vec<OutputSegment>>, // Some(..) while rendering via render_segments
+    in_generation: bool,
+    info: RenderInfo,
+    now: Option<i64>, // pinned clock for strftime_now(); None reads the system clock
+    strict: Cell<bool>, // Cell so probes like `is defined` can relax it while evaluating
+    coerce_numeric_strings: bool,
 }
 
 impl Evaluator {
     pub fn new(context: HashMap<String, Value>) -> Self {
         Self {
             scopes: vec![context],
+            segments: None,
+            in_generation: false,
+            info: RenderInfo::default(),
+            now: None,
+            strict: Cell::new(false),
+            coerce_numeric_strings: false,
         }
     }
 
-    fn get_var(&self, name: &str) -> Option<Value> {
-        for scope in self.scopes.iter().rev() {
-            if let Some(val) = scope.get(name) {
-                return Some(val.clone());
+    /// Enable strict mode: reading an undefined variable, attribute or key is an error
+    /// instead of evaluating to `Value::Null`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict.set(strict);
+    }
+
+    /// Let `==`, `!=` and the ordering operators compare an integer with a
+    /// string holding an integer (`"5"`) by its numeric value.
+    pub fn set_coerce_numeric_strings(&mut self, coerce: bool) {
+        self.coerce_numeric_strings = coerce;
+    }
+
+    /// Make `strftime_now()` format this Unix timestamp (as UTC) instead of
+    /// the current local time.
+    pub fn set_now(&mut self, timestamp: i64) {
+        self.now = Some(timestamp);
+    }
+
+    /// What the renders so far have produced; see [`RenderInfo`].
+    pub fn info(&self) -> &RenderInfo {
+        &self.info
+    }
+
+    /// Evaluate with strict mode suspended. Used where undefined is an expected
+    /// input (`is defined`, `| default`), as with Jinja2's `StrictUndefined`.
+    fn eval_lenient(&self, expr: &Expr) -> Result<Value, String> {
+        let strict = self.strict.replace(false);
+        let result = self.eval_expr(expr);
+        self.strict.set(strict);
+        result
+    }
+
+    /// Render the template, returning the output split into segments tagged by
+    /// origin. Concatenating the segment texts yields exactly what
+    /// [`render`](Self::render) returns.
+    pub fn render_segments(&mut self, template: &[Node]) -> Result<Vec<OutputSegment>, String> {
+        self.segments = Some(Vec::new());
+        let result = self.render(template);
+        let segments = self.segments.take().unwrap_or_default();
+        result.map(|_| segments)
+    }
+
+    fn push_segment(&mut self, text: &str, origin: Origin) {
+        let origin = if self.in_generation { Origin::GenerationPrompt } else { origin };
+        if let Some(segments) = self.segments.as_mut() {
+            if text.is_empty() {
+                return;
+            }
+            match segments.last_mut() {
+                Some(last) if last.origin == origin => last.text.push_str(text),
+                _ => segments.push(OutputSegment { text: text.to_string(), origin }),
             }
         }
-        None
+    }
+
+    /// Evaluate an interpolated expression, collecting its output segments in
+    /// `pieces`. String concatenation chains are split so each operand is
+    /// tagged on its own; every operand is evaluated once, so the pieces always
+    /// concatenate to the value's output.
+    fn eval_segmented(&self, expr: &Expr, pieces: &mut Vec<(String, Origin)>) -> Result<Value, String> {
+        // `a + b + c` parses as `(a + b) + c`: walk down the left spine to
+        // split the chain, then fold the operands left to right.
+        let mut operands = Vec::new();
+        let mut head = expr;
+        while let Expr::BinOp(lhs, BinOp::Add, rhs) = head {
+            operands.push(&**rhs);
+            head = lhs;
+        }
+        if operands.is_empty() {
+            let val = self.eval_expr(expr)?;
+            let mut text = String::new();
+            val.write_output(&mut text);
+            pieces.push((text, expr_origin(expr)));
+            return Ok(val);
+        }
+        let mark = pieces.len();
+        let mut val = self.eval_segmented(head, pieces)?;
+        for operand in operands.into_iter().rev() {
+            let r = self.eval_segmented(operand, pieces)?;
+            val = self.binop(val, &BinOp::Add, r)?;
+        }
+        if !matches!(val, Value::String(_)) {
+            // A numeric sum is one piece of output, not one per operand.
+            pieces.truncate(mark);
+            let mut text = String::new();
+            val.write_output(&mut text);
+            pieces.push((text, Origin::Literal));
+        }
+        Ok(val)
+    }
+
+    fn get_var_ref(&self, name: &str) -> Option<&Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /// Borrow the value at a plain lookup path (`tools`, `message.content`,
+    /// `messages[i]['role']`) so only the final value is cloned, not every
+    /// container on the way to it. Returns `None` for other expressions and
+    /// for missing steps; `eval_expr` then takes its owned path, which also
+    /// produces the strict-mode errors.
+    fn eval_ref(&self, expr: &Expr) -> Option<&Value> {
+        match expr {
+            Expr::Var(name) => self.get_var_ref(name),
+            Expr::Attribute(obj, attr) => match self.eval_ref(obj)? {
+                Value::Map(m) => m.get(attr),
+                _ => None,
+            },
+            Expr::Index(obj, idx) => match (self.eval_ref(obj)?, self.eval_expr(idx).ok()?) {
+                (Value::Map(m), Value::String(k)) => m.get(&k),
+                (Value::Array(a), Value::Int(i)) => a.get(resolve_index(i, a.len()).ok()?),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
     fn push_scope(&mut self) {
@@ -52,50 +926,261 @@ impl Evaluator {
         self.scopes.pop();
     }
 
+    /// Bind a loop item to its target names, unpacking `k, v` style targets.
+    fn bind_targets(&mut self, targets: &[String], item: Value) -> Result<(), String> {
+        match (targets, item) {
+            ([name], item) => self.set_local(name.clone(), item),
+            (names, Value::Array(values)) if values.len() == names.len() => {
+                for (name, value) in names.iter().zip(values) {
+                    self.set_local(name.clone(), value);
+                }
+            }
+            (names, item) => {
+                return Err(format!("Cannot unpack {:?} into {} loop targets", item, names.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bind a `{% set %}` target. A dotted target such as `ns.found` updates
+    /// the field in place on the map found through the scope chain, so a
+    /// `namespace()` carries state out of loop bodies.
+    fn assign(&mut self, target: &str, value: Value) -> Result<(), String> {
+        let Some((base, path)) = target.split_once('.') else {
+            self.set_local(target.to_string(), value);
+            return Ok(());
+        };
+        let Some(mut current) = self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(base)) else {
+            return Err(format!("Cannot assign to '{}': '{}' is undefined", target, base));
+        };
+        let mut fields = path.split('.').peekable();
+        while let Some(field) = fields.next() {
+            let Value::Map(map) = current else {
+                return Err(format!("Cannot assign to '{}': {:?} is not a namespace", target, current));
+            };
+            if fields.peek().is_none() {
+                map.insert(field.to_string(), value);
+                return Ok(());
+            }
+            current = match map.get_mut(field) {
+                Some(next) => next,
+                None => return Err(format!("Cannot assign to '{}': '{}' not found", target, field)),
+            };
+        }
+        Ok(())
+    }
+
     fn set_local(&mut self, name: String, value: Value) {
         if let Some(scope) = self.scopes.last_mut() {
             scope.insert(name, value);
         }
     }
 
-    pub fn render(&mut self, template: &Template) -> Result<String, String> {
+    /// Render `template`, feeding the loop at index `loop_at` (see
+    /// [`single_pass_loop`]) from `items` one element at a time instead of
+    /// evaluating its iterable.
+    pub fn render_streamed(
+        &mut self,
+        template: &[Node],
+        loop_at: usize,
+        items: &mut dyn Iterator<Item = Value>,
+    ) -> Result<String, String> {
+        let Some(Node::For { targets, filter: None, body, else_body, .. }) = template.get(loop_at) else {
+            return Err(format!("Node {} is not an unfiltered for loop", loop_at));
+        };
         let mut output = String::new();
+        self.render_into(&template[..loop_at], &mut output)?;
+        let mut items = items.peekable();
+        let mut i = 0;
+        while let Some(item) = items.next() {
+            let loop_map = loop_vars(i, items.peek().is_none());
+            self.render_iteration(targets, item, loop_map, body, &mut output)?;
+            i += 1;
+        }
+        if let (0, Some(else_body)) = (i, else_body) {
+            self.render_into(else_body, &mut output)?;
+        }
+        self.render_into(&template[loop_at + 1..], &mut output)?;
+        Ok(output)
+    }
+
+    /// Render `body` to a string without recording output segments, for
+    /// blocks whose text is post-processed or captured rather than emitted.
+    fn render_detached(&mut self, body: &[Node]) -> Result<String, String> {
+        let outer = self.segments.take();
+        let emitted = self.info.generation_prompt_emitted;
+        let mut rendered = String::new();
+        let result = self.render_into(body, &mut rendered);
+        self.segments = outer;
+        self.info.generation_prompt_emitted = emitted;
+        result.map(|()| rendered)
+    }
+
+    /// True if the (truthy) branch condition `cond` holds only because
+    /// `add_generation_prompt` is set: the flag is truthy and the condition
+    /// turns false without it. Negated guards such as
+    /// `not add_generation_prompt` therefore never mark their branch.
+    fn opened_by_generation_flag(&mut self, cond: &Expr) -> bool {
+        self.truthy_without_generation_flag(cond) == Some(false)
+    }
+
+    /// True if an `{% else %}` reached after the (falsy) `cases` was taken only
+    /// because `add_generation_prompt` is set: without it an earlier case
+    /// would have matched, as in `{% if not add_generation_prompt %}`.
+    fn else_opened_by_generation_flag(&mut self, cases: &[(Expr, Template)]) -> bool {
+        cases.iter().any(|(cond, _)| self.truthy_without_generation_flag(cond) == Some(true))
+    }
+
+    /// Truthiness of `cond` with `add_generation_prompt` forced to false, or
+    /// `None` when the flag is not set or `cond` does not mention it.
+    fn truthy_without_generation_flag(&mut self, cond: &Expr) -> Option<bool> {
+        const FLAG: &str = "add_generation_prompt";
+        if !references_var(cond, FLAG) || !self.get_var_ref(FLAG).is_some_and(Value::is_truthy) {
+            return None;
+        }
+        self.push_scope();
+        self.set_local(FLAG.to_string(), Value::Bool(false));
+        let without_flag = self.eval_expr(cond).map(|v| v.is_truthy());
+        self.pop_scope();
+        without_flag.ok()
+    }
+
+    /// Send `text` to `out`, noting whether it belongs to the generation prompt.
+    fn emit(&mut self, out: &mut dyn Output, text: &str) -> Result<(), String> {
+        if self.in_generation && !text.is_empty() {
+            self.info.generation_prompt_emitted = true;
+        }
+        out.push(text)
+    }
+
+    /// Render one pass of a loop body with `targets` and `loop` bound.
+    ///
+    /// The iteration runs in its own scope: the targets, `loop` and any plain
+    /// `{% set %}` in the body are dropped afterwards, while dotted sets such
+    /// as `ns.found` write through to the namespace in the enclosing scope.
+    fn render_iteration(
+        &mut self,
+        targets: &[String],
+        item: Value,
+        loop_map: BTreeMap<String, Value>,
+        body: &[Node],
+        out: &mut dyn Output,
+    ) -> Result<(), String> {
+        self.push_scope();
+        if let Err(msg) = self.bind_targets(targets, item) {
+            self.pop_scope();
+            return Err(msg);
+        }
+        self.set_local("loop".to_string(), Value::Map(loop_map));
+        let rendered = self.render_into(body, out);
+        self.pop_scope();
+        rendered
+    }
+
+    pub fn render(&mut self, template: &[Node]) -> Result<String, String> {
+        let mut output = String::with_capacity(self.output_capacity(template));
+        self.render_into(template, &mut output)?;
+        Ok(output)
+    }
+
+    /// Estimated length of `template`'s output, so `render` can size its
+    /// buffer once instead of growing it message by message.
+    fn output_capacity(&self, template: &[Node]) -> usize {
+        let Some(Value::Array(messages)) = self.get_var_ref("messages") else {
+            return literal_capacity(template, 0).min(MAX_CAPACITY_HINT);
+        };
+        let content: usize = messages
+            .iter()
+            .filter_map(|m| match m {
+                Value::Map(fields) => match fields.get("content") {
+                    Some(Value::String(s)) => Some(s.len()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .sum();
+        literal_capacity(template, messages.len()).saturating_add(content).min(MAX_CAPACITY_HINT)
+    }
+
+    /// Render `template` straight into `w` as output is produced, instead of
+    /// building the whole result in memory. Writes are not buffered, so wrap
+    /// files and sockets in a `BufWriter`.
+    ///
+    /// Render failures are reported as [`io::ErrorKind::InvalidData`] errors
+    /// carrying the evaluator's message; write failures are returned as-is.
+    pub fn render_to<W: Write>(&mut self, template: &[Node], w: &mut W) -> io::Result<()> {
+        let mut sink = IoOutput { writer: w, error: None };
+        match self.render_into(template, &mut sink) {
+            Ok(()) => Ok(()),
+            Err(msg) => Err(sink.error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidData, msg))),
+        }
+    }
+
+    fn render_into(&mut self, template: &[Node], out: &mut dyn Output) -> Result<(), String> {
         for node in template {
             match node {
-                Node::Text(s) => output.push_str(s),
+                Node::Text(s) => {
+                    self.emit(out, s)?;
+                    self.push_segment(s, Origin::Literal);
+                }
                 Node::Var(expr) => {
-                    let val = self.eval_expr(expr)?;
-                    match val {
-                        Value::String(s) => output.push_str(&s),
-                        Value::Int(n)    => output.push_str(&n.to_string()),
-                        Value::Bool(b)   => output.push_str(if b { "True" } else { "False" }),
-                        Value::Null      => {} // Jinja2 renders None/null as empty
-                        _ => return Err(format!("Cannot render complex type {:?}", val)),
+                    let mut pieces = Vec::new();
+                    let val = match self.segments {
+                        Some(_) => self.eval_segmented(expr, &mut pieces)?,
+                        None => self.eval_expr(expr)?,
+                    };
+                    match &val {
+                        Value::String(s) => self.emit(out, s)?,
+                        other => {
+                            let mut text = String::new();
+                            other.write_output(&mut text);
+                            self.emit(out, &text)?;
+                        }
+                    }
+                    for (text, origin) in pieces {
+                        self.push_segment(&text, origin);
                     }
                 }
-                Node::For { target, iterable, body } => {
-                    let iter_val = self.eval_expr(iterable)?;
-                    match iter_val {
-                        Value::Array(items) => {
-                            let len = items.len();
-                            for (i, item) in items.into_iter().enumerate() {
-                                self.push_scope();
-                                self.set_local(target.clone(), item);
-
-                                // Inject loop.* variables
-                                let mut loop_map = HashMap::new();
-                                loop_map.insert("index0".to_string(), Value::Int(i as i64));
-                                loop_map.insert("index".to_string(),  Value::Int(i as i64 + 1));
-                                loop_map.insert("first".to_string(),  Value::Bool(i == 0));
-                                loop_map.insert("last".to_string(),   Value::Bool(i == len - 1));
-                                self.set_local("loop".to_string(), Value::Map(loop_map));
-
-                                output.push_str(&self.render(body)?);
-                                self.pop_scope();
+                Node::For { targets, iterable, filter, body, else_body } => {
+                    let mut items = match self.eval_expr(iterable)? {
+                        Value::Null => Vec::new(), // Missing iterable = empty loop (Jinja2 behavior)
+                        other => match iter_items(other) {
+                            Ok(items) => items,
+                            Err(msg) if self.strict.get() => return Err(msg),
+                            Err(msg) => {
+                                self.info.warnings.push(format!("{}; loop skipped", msg));
+                                Vec::new()
+                            }
+                        },
+                    };
+                    // Apply the loop filter first so loop.* reflects the
+                    // filtered sequence, as in Jinja2.
+                    if let Some(cond) = filter {
+                        let mut kept = Vec::with_capacity(items.len());
+                        for item in items {
+                            self.push_scope();
+                            let keep = self
+                                .bind_targets(targets, item.clone())
+                                .and_then(|_| self.eval_expr(cond));
+                            self.pop_scope();
+                            if keep?.is_truthy() {
+                                kept.push(item);
                             }
                         }
-                        Value::Null => {} // Missing iterable = skip loop (Jinja2 behavior)
-                        _ => return Err(format!("Expected array for loop, got {:?}", iter_val)),
+                        items = kept;
+                    }
+                    if items.is_empty() {
+                        if let Some(else_body) = else_body {
+                            self.render_into(else_body, out)?;
+                        }
+                    }
+                    let len = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let mut loop_map = loop_vars(i, i == len - 1);
+                        loop_map.insert("length".to_string(), Value::Int(len as i64));
+                        loop_map.insert("revindex".to_string(),  Value::Int((len - i) as i64));
+                        loop_map.insert("revindex0".to_string(), Value::Int((len - i - 1) as i64));
+                        self.render_iteration(targets, item, loop_map, body, out)?;
                     }
                 }
                 Node::If { cases, else_body } => {
@@ -103,35 +1188,521 @@ impl Evaluator {
                     for (cond, body) in cases {
                         let val = self.eval_expr(cond)?;
                         if val.is_truthy() {
-                            output.push_str(&self.render(body)?);
+                            let outer = self.in_generation;
+                            self.in_generation |= self.opened_by_generation_flag(cond);
+                            let rendered = self.render_into(body, out);
+                            self.in_generation = outer;
+                            rendered?;
                             matched = true;
                             break;
                         }
                     }
                     if !matched {
                         if let Some(body) = else_body {
-                            output.push_str(&self.render(body)?);
+                            let outer = self.in_generation;
+                            self.in_generation |= self.else_opened_by_generation_flag(cases);
+                            let rendered = self.render_into(body, out);
+                            self.in_generation = outer;
+                            rendered?;
                         }
                     }
                 }
+                Node::Generation(body) => {
+                    // Only meaningful for assistant-token masking; otherwise the
+                    // tags are transparent and the body renders in place.
+                    self.render_into(body, out)?;
+                }
+                Node::FilterBlock { name, args, body } => {
+                    // Segments recorded inside the body would no longer match the
+                    // filtered text, so record the result as a single segment.
+                    let rendered = self.render_detached(body)?;
+                    let filtered = self.apply_filter(Value::String(rendered), name, args)?;
+                    let mut text = String::new();
+                    filtered.write_output(&mut text);
+                    self.push_segment(&text, Origin::Literal);
+                    self.emit(out, &text)?;
+                }
+                Node::SetBlock { name, body } => {
+                    let captured = self.render_detached(body)?;
+                    self.assign(name, Value::String(captured))?;
+                }
                 Node::Set { name, expr } => {
                     // {% set name = expr %} — assigns into the current scope.
                     // If blocks don't push scopes, so this correctly modifies
                     // the enclosing for-loop scope (or root scope) as Jinja2 does.
                     let val = self.eval_expr(expr)?;
-                    self.set_local(name.clone(), val);
+                    self.assign(name, val)?;
                 }
             }
         }
-        Ok(output)
+        Ok(())
+    }
+
+    /// Resolve `[start:end]` against a sequence of `len` items: negative
+    /// bounds count from the end, and out-of-range bounds clamp like Python.
+    fn slice_range(&self, start: Option<&Expr>, end: Option<&Expr>, len: usize) -> Result<(usize, usize), String> {
+        let bound = |expr: Option<&Expr>, default: usize| -> Result<usize, String> {
+            Ok(match expr.map(|e| self.eval_expr(e)).transpose()? {
+                Some(Value::Int(n)) if n < 0 => len.saturating_sub(usize::try_from(n.unsigned_abs()).unwrap_or(usize::MAX)),
+                Some(Value::Int(n)) => usize::try_from(n).unwrap_or(usize::MAX).min(len),
+                _ => default,
+            })
+        };
+        let start = bound(start, 0)?;
+        let end = bound(end, len)?.max(start);
+        Ok((start, end))
+    }
+
+    /// `is defined`: true if the variable, attribute or key exists, even when
+    /// it holds an explicit null. Only a missing name is undefined.
+    fn is_defined(&self, expr: &Expr) -> Result<bool, String> {
+        Ok(match expr {
+            Expr::Var(name) => self.get_var_ref(name).is_some(),
+            Expr::Attribute(obj, attr) => match self.eval_lenient(obj)? {
+                Value::Map(m) => m.contains_key(attr),
+                _ => false,
+            },
+            Expr::Index(obj, key) => match (self.eval_lenient(obj)?, self.eval_lenient(key)?) {
+                (Value::Map(m), Value::String(k)) => m.contains_key(&k),
+                (Value::Array(a), Value::Int(i)) => resolve_index(i, a.len()).is_ok(),
+                (Value::String(s), Value::Int(i)) => resolve_index(i, s.chars().count()).is_ok(),
+                _ => false,
+            },
+            other => !matches!(self.eval_lenient(other)?, Value::Null),
+        })
+    }
+
+    /// Tests other than `defined`, which only need the value of `expr`.
+    fn value_test(&self, expr: &Expr, test_name: &str) -> Result<bool, String> {
+        let val = match test_name {
+            "none" | "None" => self.eval_lenient(expr)?,
+            _ => self.eval_expr(expr)?,
+        };
+        Ok(match test_name {
+            "none" | "None"   =>  matches!(val, Value::Null),
+            "string"          =>  matches!(val, Value::String(_)),
+            "integer"         =>  matches!(val, Value::Int(_)),
+            "float"           =>  matches!(val, Value::Float(_)),
+            "number"          =>  matches!(val, Value::Int(_) | Value::Float(_)),
+            "boolean"         =>  matches!(val, Value::Bool(_)),
+            "iterable" | "sequence" => iter_items(val).is_ok(),
+            "mapping"         =>  matches!(val, Value::Map(_)),
+            "true"            =>  val.is_truthy(),
+            "false"           => !val.is_truthy(),
+            // Unknown test name — safe false (graceful degradation)
+            _                 => false,
+        })
+    }
+
+    /// Evaluate the arguments named in `params`, matching keywords by name and
+    /// the rest by position. Entries are `None` for arguments not supplied.
+    fn eval_args(&self, args: &[Arg], params: &[&str]) -> Result<Vec<Option<Value>>, String> {
+        let mut values = Vec::with_capacity(params.len());
+        for (pos, name) in params.iter().enumerate() {
+            values.push(arg(args, pos, name).map(|e| self.eval_expr(e)).transpose()?);
+        }
+        Ok(values)
+    }
+
+    /// Apply filter `name` with `args` to an already-evaluated value.
+    fn apply_filter(&self, val: Value, name: &str, args: &[Arg]) -> Result<Value, String> {
+        match name {
+            "trim" => match val {
+                Value::String(mut s) => {
+                    s.truncate(s.trim_end().len());
+                    let leading = s.len() - s.trim_start().len();
+                    s.drain(..leading);
+                    Ok(Value::String(s))
+                }
+                other => Ok(other),
+            },
+            "default" | "d" => {
+                let is_falsy = matches!(&val, Value::Null)
+                    || matches!(&val, Value::String(s) if s.is_empty());
+                if is_falsy {
+                    if let Some(default_expr) = arg(args, 0, "default_value") {
+                        self.eval_expr(default_expr)
+                    } else {
+                        Ok(Value::String(String::new()))
+                    }
+                } else {
+                    Ok(val)
+                }
+            }
+            // Collapse every whitespace run to one space and trim the ends
+            "collapse_whitespace" => match val {
+                Value::String(s) => Ok(Value::String(s.split_whitespace().collect::<Vec<_>>().join(" "))),
+                other => Ok(other),
+            },
+            "upper" => match val {
+                Value::String(s) => Ok(Value::String(s.to_uppercase())),
+                other => Ok(other),
+            },
+            "lower" => match val {
+                Value::String(s) => Ok(Value::String(s.to_lowercase())),
+                other => Ok(other),
+            },
+            "capitalize" => match val {
+                Value::String(s) => {
+                    let mut chars = s.chars();
+                    Ok(Value::String(match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+                        None => s,
+                    }))
+                }
+                other => Ok(other),
+            },
+            "title" => match val {
+                Value::String(s) => Ok(Value::String(title_case(&s))),
+                other => Ok(other),
+            },
+            // int(default=0): parse strings (including a truncated decimal like
+            // '3.7'); anything unparseable becomes `default`, as in Jinja2.
+            "int" => {
+                let parsed = match &val {
+                    Value::Int(n) => Some(*n),
+                    Value::Float(f) if f.is_finite() => Some(f.trunc() as i64),
+                    Value::Bool(b) => Some(*b as i64),
+                    Value::String(s) => parse_int(s.trim()),
+                    _ => None,
+                };
+                match (parsed, arg(args, 0, "default")) {
+                    (Some(n), _) => Ok(Value::Int(n)),
+                    (None, Some(default)) => self.eval_expr(default),
+                    (None, None) => Ok(Value::Int(0)),
+                }
+            }
+            // safe marks markup as already escaped. Autoescape is always off, so
+            // nothing is escaped unless `| e` asks for it and `safe` passes through.
+            "safe" => Ok(val),
+            // escape / e: HTML-escape as markupsafe does, stringifying non-strings first
+            "escape" | "e" => {
+                let mut text = String::new();
+                val.write_output(&mut text);
+                Ok(Value::String(html_escape(&text)))
+            }
+            "string" => match val {
+                Value::String(s) => Ok(Value::String(s)),
+                other => {
+                    let mut text = String::new();
+                    other.write_output(&mut text);
+                    Ok(Value::String(text))
+                }
+            },
+            "length" | "count" => match &val {
+                Value::String(s)  => Ok(Value::Int(s.chars().count() as i64)),
+                Value::Array(a)   => Ok(Value::Int(a.len() as i64)),
+                Value::Map(m)     => Ok(Value::Int(m.len() as i64)),
+                Value::Null       => Ok(Value::Int(0)), // undefined | length == 0 in Jinja2
+                other => Err(format!("object of type {:?} has no length", other)),
+            },
+            "first" => match val {
+                Value::Array(a)  => Ok(a.into_iter().next().unwrap_or(Value::Null)),
+                Value::String(s) => Ok(s.chars().next().map(|c| Value::String(c.to_string())).unwrap_or(Value::Null)),
+                other => Err(format!("first expects a list or string, got {:?}", other)),
+            },
+            "last" => match val {
+                Value::Array(a)  => Ok(a.into_iter().next_back().unwrap_or(Value::Null)),
+                Value::String(s) => Ok(s.chars().next_back().map(|c| Value::String(c.to_string())).unwrap_or(Value::Null)),
+                other => Err(format!("last expects a list or string, got {:?}", other)),
+            },
+            "join" => match val {
+                Value::Array(items) => {
+                    let sep = match arg(args, 0, "d") {
+                        Some(e) => self.eval_expr(e)?,
+                        None => Value::String(String::new()),
+                    };
+                    let mut sep_str = String::new();
+                    sep.write_output(&mut sep_str);
+                    let mut out = String::new();
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(&sep_str);
+                        }
+                        item.write_output(&mut out);
+                    }
+                    Ok(Value::String(out))
+                }
+                other => Err(format!("join expects a list, got {:?}", other)),
+            },
+            "list" => match val {
+                Value::Array(a)  => Ok(Value::Array(a)),
+                Value::String(s) => Ok(Value::Array(s.chars().map(|c| Value::String(c.to_string())).collect())),
+                Value::Map(m) => Ok(Value::Array(m.into_keys().map(Value::String).collect())),
+                Value::Null => Ok(Value::Array(Vec::new())),
+                other => Err(format!("list expects an iterable, got {:?}", other)),
+            },
+            // selectattr(attr, 'equalto', value): keep items whose attribute matches
+            "selectattr" => match val {
+                Value::Array(items) => {
+                    let attr = match arg(args, 0, "attr").map(|e| self.eval_expr(e)).transpose()? {
+                        Some(Value::String(a)) => a,
+                        other => return Err(format!("selectattr expects an attribute name, got {:?}", other)),
+                    };
+                    let test = match arg(args, 1, "test").map(|e| self.eval_expr(e)).transpose()? {
+                        // selectattr('tool_calls') keeps items whose attribute is truthy
+                        None => None,
+                        Some(Value::String(t)) => Some(t),
+                        Some(other) => return Err(format!("selectattr expects a test name, got {:?}", other)),
+                    };
+                    let keep_equal = match test.as_deref() {
+                        None => None,
+                        Some("equalto" | "eq" | "==") => Some(true),
+                        Some("ne" | "!=") => Some(false),
+                        Some(t) => return Err(format!("selectattr: unsupported test '{}'", t)),
+                    };
+                    let expected = match (keep_equal, arg(args, 2, "value")) {
+                        (None, _) => Value::Null,
+                        (Some(_), Some(e)) => self.eval_expr(e)?,
+                        (Some(_), None) => {
+                            return Err(format!("selectattr: '{}' requires a value", test.unwrap_or_default()));
+                        }
+                    };
+                    Ok(Value::Array(
+                        items
+                            .into_iter()
+                            .filter(|item| {
+                                let field = match item {
+                                    Value::Map(m) => m.get(&attr),
+                                    _ => None,
+                                };
+                                match keep_equal {
+                                    None => field.is_some_and(Value::is_truthy),
+                                    Some(keep) => (field == Some(&expected)) == keep,
+                                }
+                            })
+                            .collect(),
+                    ))
+                }
+                Value::Null => Ok(Value::Array(Vec::new())),
+                other => Err(format!("selectattr expects a list, got {:?}", other)),
+            },
+            // str.strip/lstrip/rstrip([chars]): whitespace, or any of `chars`
+            "strip" | "lstrip" | "rstrip" => match val {
+                Value::String(s) => {
+                    let chars = match arg(args, 0, "chars").map(|e| self.eval_expr(e)).transpose()? {
+                        None | Some(Value::Null) => None,
+                        Some(Value::String(c)) => Some(c),
+                        Some(other) => return Err(format!("{}() chars must be a string, got {:?}", name, other)),
+                    };
+                    let stripped = |c: char| match &chars {
+                        Some(set) => set.contains(c),
+                        None => c.is_whitespace(),
+                    };
+                    let mut out = s.as_str();
+                    if name != "rstrip" {
+                        out = out.trim_start_matches(stripped);
+                    }
+                    if name != "lstrip" {
+                        out = out.trim_end_matches(stripped);
+                    }
+                    Ok(Value::String(out.to_string()))
+                }
+                other => Err(format!("{}() expects a string, got {:?}", name, other)),
+            },
+            // str.startswith(prefix) / str.endswith(suffix)
+            "startswith" | "endswith" => {
+                let affix = match arg(args, 0, "prefix").map(|e| self.eval_expr(e)).transpose()? {
+                    Some(Value::String(a)) => a,
+                    other => return Err(format!("{}() expects a string argument, got {:?}", name, other)),
+                };
+                match val {
+                    Value::String(s) if name == "startswith" => Ok(Value::Bool(s.starts_with(affix.as_str()))),
+                    Value::String(s) => Ok(Value::Bool(s.ends_with(affix.as_str()))),
+                    other => Err(format!("{}() expects a string, got {:?}", name, other)),
+                }
+            }
+            // dict.items() / .keys() / .values(), in sorted key order like `| list`
+            "items" | "keys" | "values" => match val {
+                Value::Map(m) => Ok(Value::Array(
+                    m.into_iter()
+                        .map(|(k, v)| match name {
+                            "items" => Value::Array(vec![Value::String(k), v]),
+                            "keys" => Value::String(k),
+                            _ => v,
+                        })
+                        .collect(),
+                )),
+                Value::Null => Ok(Value::Array(Vec::new())),
+                other => Err(format!("{}() expects a mapping, got {:?}", name, other)),
+            },
+            // loop.cycle(a, b, ...) — the argument at index0 modulo the count.
+            "cycle" => {
+                let Value::Map(m) = &val else {
+                    return Err(format!("cycle() is only available on loop, got {:?}", val));
+                };
+                let Some(Value::Int(index0)) = m.get("index0") else {
+                    return Err("cycle() is only available on loop".to_string());
+                };
+                if args.is_empty() {
+                    return Err("cycle() requires at least one value".to_string());
+                }
+                let (_, chosen) = &args[*index0 as usize % args.len()];
+                self.eval_expr(chosen)
+            }
+            // dict.get(key, default=None) — never errors, even in strict mode,
+            // matching Python/Jinja2 where `get` exists to probe optional keys.
+            "get" => match val {
+                Value::Map(mut m) => {
+                    let key = match arg(args, 0, "key") {
+                        Some(e) => self.eval_expr(e)?,
+                        None => return Err("get() requires a key".to_string()),
+                    };
+                    let found = match key {
+                        Value::String(k) => m.remove(&k),
+                        _ => None,
+                    };
+                    match (found, arg(args, 1, "default")) {
+                        (Some(v), _)          => Ok(v),
+                        (None, Some(default)) => self.eval_expr(default),
+                        (None, None)          => Ok(Value::Null),
+                    }
+                }
+                other => Err(format!("get() expects a mapping, got {:?}", other)),
+            },
+            // tojson(indent=None): JSON for tool schemas and arguments
+            "tojson" => {
+                let indent = match arg(args, 0, "indent") {
+                    Some(e) => self.eval_expr(e)?,
+                    None => Value::Null,
+                };
+                match indent {
+                    Value::Null => Ok(Value::String(val.to_json())),
+                    Value::Int(n) if n >= 0 => Ok(Value::String(val.to_json_pretty(n as usize))),
+                    other => Err(format!("tojson indent must be a non-negative integer, got {:?}", other)),
+                }
+            }
+            // str.split(sep) method; without a separator splits on whitespace runs
+            "split" => match val {
+                Value::String(s) => {
+                    let sep = match arg(args, 0, "sep") {
+                        Some(e) => self.eval_expr(e)?,
+                        None => Value::Null,
+                    };
+                    let parts: Vec<Value> = match sep {
+                        Value::Null => s.split_whitespace().map(|p| Value::String(p.to_string())).collect(),
+                        Value::String(sep) if sep.is_empty() => return Err("split: empty separator".to_string()),
+                        Value::String(sep) => s.split(sep.as_str()).map(|p| Value::String(p.to_string())).collect(),
+                        other => return Err(format!("split separator must be a string, got {:?}", other)),
+                    };
+                    Ok(Value::Array(parts))
+                }
+                other => Err(format!("split expects a string, got {:?}", other)),
+            },
+            // replace(old, new[, count]): Python str.replace; a negative count replaces all
+            "replace" => match val {
+                Value::String(s) => {
+                    let params = self.eval_args(args, &["old", "new", "count"])?;
+                    let (old, new) = match (&params[0], &params[1]) {
+                        (Some(Value::String(old)), Some(Value::String(new))) => (old.as_str(), new.as_str()),
+                        _ => return Err(format!("replace expects two string arguments, got {:?}", params)),
+                    };
+                    match &params[2] {
+                        None => Ok(Value::String(s.replace(old, new))),
+                        Some(Value::Int(n)) if *n < 0 => Ok(Value::String(s.replace(old, new))),
+                        Some(Value::Int(n)) => Ok(Value::String(s.replacen(old, new, *n as usize))),
+                        Some(other) => Err(format!("replace count must be an integer, got {:?}", other)),
+                    }
+                }
+                other => Err(format!("replace expects a string, got {:?}", other)),
+            },
+            // strip_think(open='<think>', close='</think>'): removes every complete
+            // reasoning span emitted by reasoning models. An unclosed opening tag
+            // leaves the remainder of the content intact.
+            "strip_think" => match val {
+                Value::String(s) => {
+                    let open = match arg(args, 0, "open") {
+                        Some(e) => self.eval_expr(e)?,
+                        None => Value::String("<think>".to_string()),
+                    };
+                    let close = match arg(args, 1, "close") {
+                        Some(e) => self.eval_expr(e)?,
+                        None => Value::String("</think>".to_string()),
+                    };
+                    match (open, close) {
+                        (Value::String(o), Value::String(c)) => {
+                            Ok(Value::String(strip_spans(&s, &o, &c)))
+                        }
+                        _ => Err("strip_think tags must be strings".to_string()),
+                    }
+                }
+                other => Ok(other),
+            },
+            // wordwrap(width=79, break_long_words=true, wrapstring='\n'). The
+            // newlines land only in the piped value, never in template structure.
+            "wordwrap" => match val {
+                Value::String(s) => {
+                    let params = self.eval_args(args, &["width", "break_long_words", "wrapstring"])?;
+                    let width = match &params[0] {
+                        None => 79,
+                        Some(Value::Int(n)) if *n > 0 => *n as usize,
+                        Some(other) => return Err(format!("wordwrap width must be a positive integer, got {:?}", other)),
+                    };
+                    let break_long_words = params[1].as_ref().is_none_or(Value::is_truthy);
+                    let wrapstring = match &params[2] {
+                        Some(Value::String(w)) => w.as_str(),
+                        _ => "\n",
+                    };
+                    Ok(Value::String(wordwrap(&s, width, break_long_words, wrapstring)))
+                }
+                other => Ok(other),
+            },
+            // truncate(length=255, killwords=false, end='...', leeway=5)
+            "truncate" => match val {
+                Value::String(s) => {
+                    let params = self.eval_args(args, &["length", "killwords", "end", "leeway"])?;
+                    let length = match &params[0] {
+                        None => 255,
+                        Some(Value::Int(n)) if *n >= 0 => *n as usize,
+                        Some(other) => return Err(format!("truncate length must be a non-negative integer, got {:?}", other)),
+                    };
+                    let killwords = params[1].as_ref().is_some_and(Value::is_truthy);
+                    let end = match &params[2] {
+                        Some(Value::String(e)) => e.as_str(),
+                        _ => "...",
+                    };
+                    let leeway = match &params[3] {
+                        Some(Value::Int(n)) if *n >= 0 => *n as usize,
+                        _ => 5,
+                    };
+                    Ok(Value::String(truncate(&s, length, killwords, end, leeway)))
+                }
+                other => Ok(other),
+            },
+            // Python %-formatting: '%s: %s' | format(a, b). Supports %s, %d and %%.
+            "format" => match val {
+                Value::String(fmt) => {
+                    let mut values = Vec::with_capacity(args.len());
+                    for (_, a) in args.iter().filter(|(name, _)| name.is_none()) {
+                        values.push(self.eval_expr(a)?);
+                    }
+                    format_percent(&fmt, &values).map(Value::String)
+                }
+                other => Err(format!("format expects a string, got {:?}", other)),
+            },
+            // Unknown filter: return value unchanged (graceful degradation)
+            _ => Ok(val),
+        }
     }
 
     fn eval_expr(&self, expr: &Expr) -> Result<Value, String> {
         match expr {
             Expr::StringLit(s) => Ok(Value::String(s.clone())),
             Expr::IntLit(n)    => Ok(Value::Int(*n)),
+            Expr::FloatLit(f)  => Ok(Value::Float(*f)),
             Expr::BoolLit(b)   => Ok(Value::Bool(*b)),
-            Expr::Var(name)    => Ok(self.get_var(name).unwrap_or(Value::Null)),
+            Expr::ListLit(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(self.eval_expr(item)?);
+                }
+                Ok(Value::Array(values))
+            }
+            Expr::Var(name) => match self.get_var_ref(name) {
+                Some(v) => Ok(v.clone()),
+                None if self.strict.get() => Err(format!("Variable '{}' is undefined", name)),
+                None => Ok(Value::Null),
+            },
 
             Expr::Not(inner) => {
                 let val = self.eval_expr(inner)?;
@@ -139,31 +1710,45 @@ impl Evaluator {
             }
 
             Expr::Attribute(obj, attr) => {
+                if let Some(v) = self.eval_ref(expr) {
+                    return Ok(v.clone());
+                }
                 let val = self.eval_expr(obj)?;
                 match val {
-                    Value::Map(m) => Ok(m.get(attr).cloned().unwrap_or(Value::Null)),
+                    Value::Map(mut m) => match m.remove(attr) {
+                        Some(v) => Ok(v),
+                        None if self.strict.get() => Err(format!("Attribute '{}' not found", attr)),
+                        // Missing keys are undefined, so `{% if message.tool_calls %}` is just false
+                        None => Ok(Value::Null),
+                    },
+                    _ if self.strict.get() => Err(format!("{:?} has no attribute '{}'", val, attr)),
                     // Graceful degradation: attribute access on non-map returns Null
                     _ => Ok(Value::Null),
                 }
             }
 
             Expr::Index(obj, idx) => {
+                if let Some(v) = self.eval_ref(expr) {
+                    return Ok(v.clone());
+                }
                 let val     = self.eval_expr(obj)?;
                 let idx_val = self.eval_expr(idx)?;
                 match (val, idx_val) {
                     // Map key access: map['key']
-                    (Value::Map(m), Value::String(s)) => {
-                        Ok(m.get(&s).cloned().unwrap_or(Value::Null))
-                    }
+                    (Value::Map(mut m), Value::String(s)) => match m.remove(&s) {
+                        Some(v) => Ok(v),
+                        None if self.strict.get() => Err(format!("Key '{}' not found", s)),
+                        None => Ok(Value::Null),
+                    },
                     // Array access with integer (including negative)
-                    (Value::Array(a), Value::Int(i)) => {
-                        let len = a.len() as i64;
-                        let idx = if i < 0 { len + i } else { i };
-                        if idx < 0 || idx >= len {
-                            Err(format!("Index {} out of bounds (len={})", i, len))
-                        } else {
-                            Ok(a[idx as usize].clone())
-                        }
+                    (Value::Array(mut a), Value::Int(i)) => {
+                        let idx = resolve_index(i, a.len())?;
+                        Ok(a.swap_remove(idx))
+                    }
+                    // String access yields a one-character string, counting chars not bytes
+                    (Value::String(s), Value::Int(i)) => {
+                        let idx = resolve_index(i, s.chars().count())?;
+                        Ok(Value::String(s.chars().nth(idx).map(String::from).unwrap_or_default()))
                     }
                     // Array access with string that parses as integer
                     (Value::Array(a), Value::String(s)) => {
@@ -175,109 +1760,112 @@ impl Evaluator {
                             Err(format!("Array index must be integer, got '{}'", s))
                         }
                     }
+                    // Indexing something undefined stays undefined, like a missing
+                    // attribute, so `message['tool_calls'][0]` guards are just false
+                    (Value::Null, _) if !self.strict.get() => Ok(Value::Null),
                     (v, i) => Err(format!("Invalid index access: {:?}[{:?}]", v, i)),
                 }
             }
 
             Expr::BinOp(lhs_expr, op, rhs_expr) => {
                 let l = self.eval_expr(lhs_expr)?;
-                let r = self.eval_expr(rhs_expr)?;
+                // Short-circuit so guards like `x is defined and x` never evaluate
+                // the right side (and trip strict mode) once the left decides.
                 match op {
-                    BinOp::Eq  => Ok(Value::Bool(l == r)),
-                    BinOp::Ne  => Ok(Value::Bool(l != r)),
-                    BinOp::And => Ok(Value::Bool(l.is_truthy() && r.is_truthy())),
-                    BinOp::Or  => Ok(Value::Bool(l.is_truthy() || r.is_truthy())),
-                    BinOp::Lt => match (l, r) {
-                        (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(a < b)),
-                        _ => Ok(Value::Bool(false)),
-                    },
-                    BinOp::Gt => match (l, r) {
-                        (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(a > b)),
-                        _ => Ok(Value::Bool(false)),
-                    },
-                    BinOp::Le => match (l, r) {
-                        (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(a <= b)),
-                        _ => Ok(Value::Bool(false)),
-                    },
-                    BinOp::Ge => match (l, r) {
-                        (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(a >= b)),
-                        _ => Ok(Value::Bool(false)),
-                    },
-                    BinOp::Add => match (l, r) {
-                        (Value::String(s1), Value::String(s2)) => Ok(Value::String(s1 + &s2)),
-                        (Value::Int(a), Value::Int(b))         => Ok(Value::Int(a + b)),
-                        (l, r) => Err(format!("'+' unsupported for {:?} and {:?}", l, r)),
-                    },
-                    BinOp::Sub => match (l, r) {
-                        (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a - b)),
-                        (l, r) => Err(format!("'-' unsupported for {:?} and {:?}", l, r)),
-                    },
-                    BinOp::Mod => match (l, r) {
-                        (Value::Int(a), Value::Int(b)) if b != 0 => Ok(Value::Int(a % b)),
-                        (Value::Int(_), Value::Int(0)) => Err("Modulo by zero".to_string()),
-                        (l, r) => Err(format!("'%' unsupported for {:?} and {:?}", l, r)),
-                    },
-                    BinOp::In => match (l, r) {
-                        (Value::String(key), Value::Map(m))      => Ok(Value::Bool(m.contains_key(&key))),
-                        (val, Value::Array(a))                   => Ok(Value::Bool(a.contains(&val))),
-                        (Value::String(needle), Value::String(h)) => Ok(Value::Bool(h.contains(needle.as_str()))),
-                        _ => Ok(Value::Bool(false)),
-                    },
-                    BinOp::NotIn => match (l, r) {
-                        (Value::String(key), Value::Map(m))      => Ok(Value::Bool(!m.contains_key(&key))),
-                        (val, Value::Array(a))                   => Ok(Value::Bool(!a.contains(&val))),
-                        (Value::String(needle), Value::String(h)) => Ok(Value::Bool(!h.contains(needle.as_str()))),
-                        _ => Ok(Value::Bool(true)),
-                    },
+                    BinOp::And if !l.is_truthy() => return Ok(Value::Bool(false)),
+                    BinOp::Or if l.is_truthy() => return Ok(Value::Bool(true)),
+                    _ => {}
                 }
+                let r = self.eval_expr(rhs_expr)?;
+                self.binop(l, op, r)
             }
 
+            // Each filter receives its input by value, so chained filters hand the
+            // same buffer down the pipeline: `trim` trims in place and read-only
+            // filters such as `length` only borrow. A chain over one string costs
+            // at most one allocation (from the variable lookup), not one per stage.
             Expr::Filter(inner, name, args) => {
-                let val = self.eval_expr(inner)?;
-                match name.as_str() {
-                    "trim" => match val {
-                        Value::String(s) => Ok(Value::String(s.trim().to_string())),
-                        other => Ok(other),
-                    },
-                    "default" | "d" => {
-                        let is_falsy = matches!(&val, Value::Null)
-                            || matches!(&val, Value::String(s) if s.is_empty());
-                        if is_falsy {
-                            if let Some(default_expr) = args.first() {
-                                self.eval_expr(default_expr)
-                            } else {
-                                Ok(Value::String(String::new()))
+                let val = match name.as_str() {
+                    "default" | "d" => self.eval_lenient(inner)?,
+                    _ => self.eval_expr(inner)?,
+                };
+                self.apply_filter(val, name, args)
+            }
+
+            Expr::Call(func_name, args) => {
+                match func_name.as_str() {
+                    // raise_exception(msg) is the HF guard for malformed conversations;
+                    // surface the message as a render error.
+                    "raise_exception" => {
+                        let mut msg = String::new();
+                        match arg(args, 0, "message") {
+                            Some(e) => self.eval_expr(e)?.write_output(&mut msg),
+                            None => msg.push_str("raise_exception() called"),
+                        }
+                        Err(msg)
+                    }
+                    // namespace(found=false) returns a Map of its keyword arguments, which
+                    // `{% set ns.found = true %}` then updates in place. A positional map
+                    // argument seeds the fields first, as in Jinja2.
+                    "namespace" => {
+                        let mut fields = BTreeMap::new();
+                        for (name, value) in args {
+                            match (name, self.eval_expr(value)?) {
+                                (Some(name), v) => {
+                                    fields.insert(name.clone(), v);
+                                }
+                                (None, Value::Map(m)) => fields.extend(m),
+                                (None, other) => return Err(format!("namespace() expects keyword arguments, got {:?}", other)),
+                            }
+                        }
+                        Ok(Value::Map(fields))
+                    }
+                    // strftime_now(format): the current date, as in newer Llama templates
+                    "strftime_now" => {
+                        let format = match arg(args, 0, "format").map(|e| self.eval_expr(e)).transpose()? {
+                            Some(Value::String(f)) => f,
+                            other => return Err(format!("strftime_now() expects a format string, got {:?}", other)),
+                        };
+                        strftime_now(&format, self.now).map(Value::String)
+                    }
+                    // range(stop) / range(start, stop[, step]), as in Python
+                    "range" => {
+                        let mut bounds = Vec::with_capacity(args.len());
+                        for (_, bound) in args {
+                            match self.eval_expr(bound)? {
+                                Value::Int(n) => bounds.push(n),
+                                other => return Err(format!("range() expects integers, got {:?}", other)),
                             }
+                        }
+                        let (start, stop, step) = match bounds[..] {
+                            [stop] => (0, stop, 1),
+                            [start, stop] => (start, stop, 1),
+                            [start, stop, step] => (start, stop, step),
+                            _ => return Err(format!("range() takes 1 to 3 arguments, got {}", bounds.len())),
+                        };
+                        if step == 0 {
+                            return Err("range() step must not be zero".to_string());
+                        }
+                        let span = i128::from(stop) - i128::from(start);
+                        let len = if (span > 0) == (step > 0) && span != 0 {
+                            (span.abs() + i128::from(step).abs() - 1) / i128::from(step).abs()
                         } else {
-                            Ok(val)
+                            0
+                        };
+                        if len > MAX_RANGE as i128 {
+                            return Err(format!("range() too big: {} items, the limit is {}", len, MAX_RANGE));
+                        }
+                        let mut items = Vec::with_capacity(len as usize);
+                        let mut i = start;
+                        while (step > 0 && i < stop) || (step < 0 && i > stop) {
+                            items.push(Value::Int(i));
+                            i = match i.checked_add(step) {
+                                Some(next) => next,
+                                None => break,
+                            };
                         }
+                        Ok(Value::Array(items))
                     }
-                    "upper" => match val {
-                        Value::String(s) => Ok(Value::String(s.to_uppercase())),
-                        other => Ok(other),
-                    },
-                    "lower" => match val {
-                        Value::String(s) => Ok(Value::String(s.to_lowercase())),
-                        other => Ok(other),
-                    },
-                    "length" | "count" => match &val {
-                        Value::String(s)  => Ok(Value::Int(s.len() as i64)),
-                        Value::Array(a)   => Ok(Value::Int(a.len() as i64)),
-                        _ => Ok(Value::Int(0)),
-                    },
-                    // Unknown filter: return value unchanged (graceful degradation)
-                    _ => Ok(val),
-                }
-            }
-
-            Expr::Call(func_name, _args) => {
-                match func_name.as_str() {
-                    // raise_exception(...) is a Jinja2 macro used in some templates as a
-                    // guard. We treat it as a no-op (return empty string) so that the
-                    // rest of the template renders correctly.
-                    "raise_exception" => Ok(Value::String(String::new())),
-                    // namespace() returns an empty Map (Jinja2 scoped namespace object)
-                    "namespace" => Ok(Value::Map(HashMap::new())),
                     // Unknown function calls return Null (renders as empty)
                     _ => Ok(Value::Null),
                 }
@@ -293,49 +1881,101 @@ impl Evaluator {
             }
 
             Expr::IsTest(inner, negated, test_name) => {
-                let val = self.eval_expr(inner)?;
                 let result = match test_name.as_str() {
-                    "defined"         => !matches!(val, Value::Null),
-                    "undefined"       =>  matches!(val, Value::Null),
-                    "none" | "None"   =>  matches!(val, Value::Null),
-                    "string"          =>  matches!(val, Value::String(_)),
-                    "integer" | "number" => matches!(val, Value::Int(_)),
-                    "boolean"         =>  matches!(val, Value::Bool(_)),
-                    "iterable" | "sequence" => matches!(val, Value::Array(_) | Value::String(_)),
-                    "mapping"         =>  matches!(val, Value::Map(_)),
-                    "true"            =>  val.is_truthy(),
-                    "false"           => !val.is_truthy(),
-                    // Unknown test name — safe false (graceful degradation)
-                    _                 => false,
+                    "defined"   => self.is_defined(inner)?,
+                    "undefined" => !self.is_defined(inner)?,
+                    _ => self.value_test(inner, test_name)?,
                 };
                 Ok(Value::Bool(if *negated { !result } else { result }))
             }
 
             Expr::Slice(obj_expr, start_expr, end_expr) => {
-                let obj = self.eval_expr(obj_expr)?;
-                match obj {
+                let (start_expr, end_expr) = (start_expr.as_deref(), end_expr.as_deref());
+                match self.eval_expr(obj_expr)? {
                     Value::Array(a) => {
-                        let len = a.len() as i64;
-                        let start = match start_expr {
-                            Some(e) => match self.eval_expr(e)? {
-                                Value::Int(n) => (if n < 0 { len + n } else { n }).clamp(0, len) as usize,
-                                _ => 0,
-                            },
-                            None => 0,
-                        };
-                        let end = match end_expr {
-                            Some(e) => match self.eval_expr(e)? {
-                                Value::Int(n) => (if n < 0 { len + n } else { n }).clamp(0, len) as usize,
-                                _ => len as usize,
-                            },
-                            None => len as usize,
-                        };
-                        Ok(Value::Array(a[start.min(a.len())..end.min(a.len())].to_vec()))
+                        let (start, end) = self.slice_range(start_expr, end_expr, a.len())?;
+                        Ok(Value::Array(a[start..end].to_vec()))
+                    }
+                    Value::String(s) => {
+                        let (start, end) = self.slice_range(start_expr, end_expr, s.chars().count())?;
+                        Ok(Value::String(s.chars().skip(start).take(end - start).collect()))
                     }
-                    // Slicing a non-array is a no-op — return original value
+                    // Slicing anything else is a no-op — return original value
                     other => Ok(other),
                 }
             }
         }
     }
+
+    /// Apply a binary operator to evaluated operands. `and`/`or` are
+    /// short-circuited by the caller before the right side is evaluated.
+    fn binop(&self, l: Value, op: &BinOp, r: Value) -> Result<Value, String> {
+        let (l, r) = match op {
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge
+                if self.coerce_numeric_strings =>
+            {
+                coerce_numeric(l, r)
+            }
+            _ => (l, r),
+        };
+        match op {
+            BinOp::Eq  => Ok(Value::Bool(l == r)),
+            BinOp::Ne  => Ok(Value::Bool(l != r)),
+            BinOp::And => Ok(Value::Bool(l.is_truthy() && r.is_truthy())),
+            BinOp::Or  => Ok(Value::Bool(l.is_truthy() || r.is_truthy())),
+            BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => match (l, r) {
+                (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(match op {
+                    BinOp::Lt => a < b,
+                    BinOp::Gt => a > b,
+                    BinOp::Le => a <= b,
+                    _         => a >= b,
+                })),
+                (l, r) => match (as_f64(&l), as_f64(&r)) {
+                    (Some(a), Some(b)) => Ok(Value::Bool(match op {
+                        BinOp::Lt => a < b,
+                        BinOp::Gt => a > b,
+                        BinOp::Le => a <= b,
+                        _         => a >= b,
+                    })),
+                    _ => Err(format!("Cannot order-compare {:?} and {:?}", l, r)),
+                },
+            },
+            BinOp::Add => match (l, r) {
+                (Value::String(s1), Value::String(s2)) => Ok(Value::String(s1 + &s2)),
+                (Value::Int(a), Value::Int(b))         => checked_int(a.checked_add(b), "+", a, b),
+                (l, r) => match (as_f64(&l), as_f64(&r)) {
+                    (Some(a), Some(b)) => Ok(Value::Float(a + b)),
+                    _ => Err(format!("'+' unsupported for {:?} and {:?}", l, r)),
+                },
+            },
+            BinOp::Sub => match (l, r) {
+                (Value::Int(a), Value::Int(b)) => checked_int(a.checked_sub(b), "-", a, b),
+                (l, r) => match (as_f64(&l), as_f64(&r)) {
+                    (Some(a), Some(b)) => Ok(Value::Float(a - b)),
+                    _ => Err(format!("'-' unsupported for {:?} and {:?}", l, r)),
+                },
+            },
+            BinOp::Mod => match (l, r) {
+                (Value::Int(a), Value::Int(b)) if b != 0 => checked_int(a.checked_rem(b), "%", a, b),
+                (Value::Int(_), Value::Int(0)) => Err("Modulo by zero".to_string()),
+                (l, r) => match (as_f64(&l), as_f64(&r)) {
+                    (Some(_), Some(0.0)) => Err("Modulo by zero".to_string()),
+                    (Some(a), Some(b)) => Ok(Value::Float(a % b)),
+                    _ => Err(format!("'%' unsupported for {:?} and {:?}", l, r)),
+                },
+            },
+            BinOp::In => match (l, r) {
+                (Value::String(key), Value::Map(m))      => Ok(Value::Bool(m.contains_key(&key))),
+                (val, Value::Array(a))                   => Ok(Value::Bool(a.contains(&val))),
+                (Value::String(needle), Value::String(h)) => Ok(Value::Bool(h.contains(needle.as_str()))),
+                _ => Ok(Value::Bool(false)),
+            },
+            BinOp::NotIn => match (l, r) {
+                (Value::String(key), Value::Map(m))      => Ok(Value::Bool(!m.contains_key(&key))),
+                (val, Value::Array(a))                   => Ok(Value::Bool(!a.contains(&val))),
+                (Value::String(needle), Value::String(h)) => Ok(Value::Bool(!h.contains(needle.as_str()))),
+                _ => Ok(Value::Bool(true)),
+            },
+        }
+    }
 }
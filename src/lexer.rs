@@ -1,3 +1,5 @@
+use crate::error::{Position, RenderError};
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Text(String),
@@ -46,25 +48,40 @@ pub enum Token {
     Ident(String),
     StringLit(String),
     IntLit(i64),
+    FloatLit(f64),
 }
 
 #[derive(Clone)]
 pub struct Tokenizer<'a> {
     input: &'a str,
     cursor: usize,
+    token_start: usize, // byte offset where the most recently returned token began
     in_tag: bool,
-    trim_blocks: bool,
+    trim_blocks: bool,   // drop the first newline after a block tag
+    lstrip_blocks: bool, // strip spaces and tabs from line start up to a block tag
     trim_next_start: bool, // set by -%} or -}} to strip whitespace from the next text
+    error: Option<RenderError>, // set when scanning hits malformed input; ends the stream
 }
 
 impl<'a> Tokenizer<'a> {
     pub fn new(input: &'a str) -> Self {
+        Self::with_options(input, true, false)
+    }
+
+    /// Create a tokenizer with Jinja2's `trim_blocks` and `lstrip_blocks`
+    /// environment options. [`new`](Self::new) uses `true` and `false`.
+    ///
+    /// A leading UTF-8 byte order mark is skipped rather than emitted as text.
+    pub fn with_options(input: &'a str, trim_blocks: bool, lstrip_blocks: bool) -> Self {
         Self {
             input,
-            cursor: 0,
+            cursor: if input.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 },
+            token_start: 0,
             in_tag: false,
-            trim_blocks: true,
+            trim_blocks,
+            lstrip_blocks,
             trim_next_start: false,
+            error: None,
         }
     }
 
@@ -72,38 +89,120 @@ impl<'a> Tokenizer<'a> {
         &self.input[self.cursor..]
     }
 
+    /// Byte offset in the input where the last token returned by
+    /// [`next_token`](Self::next_token) started.
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    /// Drop the spaces and tabs between the last line start in `text` and the
+    /// block tag that follows it. `text` begins at the cursor.
+    fn lstrip_line(&self, text: &'a str) -> &'a str {
+        let line_start = match text.rfind('\n') {
+            Some(i) => i + 1,
+            None if self.cursor == 0 || self.input[..self.cursor].ends_with('\n') => 0,
+            None => return text,
+        };
+        if text[line_start..].chars().all(|c| c == ' ' || c == '\t') {
+            &text[..line_start]
+        } else {
+            text
+        }
+    }
+
     fn advance(&mut self, n: usize) {
         self.cursor += n;
     }
 
-    pub fn next_token(&mut self) -> Option<Token> {
+    /// With `trim_blocks`, drop the newline right after a tag that just closed.
+    fn skip_block_newline(&mut self) {
+        if self.trim_blocks {
+            let after = self.remaining();
+            if after.starts_with("\r\n") {
+                self.advance(2);
+            } else if after.starts_with('\n') {
+                self.advance(1);
+            }
+        }
+    }
+
+    /// Emit the body of a `{% raw %}` block whose opening tag, `open_len`
+    /// bytes long, starts at the cursor. The body becomes a single text token
+    /// with no tags interpreted; only the `-` markers on the raw tags trim it.
+    fn scan_raw(&mut self, open_len: usize, trim_start: bool) -> Option<Token> {
+        let body = &self.remaining()[open_len..];
+        let mut search = 0;
+        let (end, close_len, trim_after) = loop {
+            let Some(i) = body[search..].find("{%") else {
+                return self.fail(|pos| RenderError::UnterminatedRaw { pos }, self.cursor);
+            };
+            let at = search + i;
+            if let Some((len, trim)) = raw_tag(&body[at..], "endraw") {
+                break (at, len, trim);
+            }
+            search = at + 2;
+        };
+        let mut text = &body[..end];
+        if trim_start {
+            text = text.trim_start();
+        }
+        if body[end..].starts_with("{%-") {
+            text = text.trim_end();
+        }
+        let text = text.to_string();
+        self.advance(open_len + end + close_len);
+        self.trim_next_start = trim_after;
+        if !trim_after {
+            self.skip_block_newline();
+        }
+        if text.is_empty() {
+            self.scan()
+        } else {
+            Some(Token::Text(text))
+        }
+    }
+
+    /// Return the next token, `Ok(None)` at end of input, or an error for
+    /// malformed input such as an unterminated comment.
+    pub fn next_token(&mut self) -> Result<Option<Token>, RenderError> {
+        let token = self.scan();
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(token),
+        }
+    }
+
+    /// Record a lexing error at byte `offset` and stop producing tokens.
+    fn fail(&mut self, err: impl FnOnce(Position) -> RenderError, offset: usize) -> Option<Token> {
+        self.error = Some(err(Position::from_offset(self.input, offset)));
+        self.cursor = self.input.len();
+        None
+    }
+
+    fn scan(&mut self) -> Option<Token> {
         let rest = self.remaining();
         if rest.is_empty() {
             return None;
         }
 
+        self.token_start = self.cursor;
+
         if !self.in_tag {
             // Jinja2 comments {# ... #} — consume entirely, emit nothing.
             // Must be checked before the general {%/{{{ scan because {#
             // shares the `{` prefix but is neither a block nor a var tag.
             if rest.starts_with("{#") {
-                // Find the closing #} — if absent, consume the rest (malformed template)
-                let close = rest.find("#}").map(|i| i + 2).unwrap_or(rest.len());
-                self.advance(close);
+                let Some(close) = rest.find("#}") else {
+                    return self.fail(|pos| RenderError::UnterminatedComment { pos }, self.cursor);
+                };
+                self.advance(close + 2);
                 // Respect trim_blocks: eat the newline that follows #} if present
-                if self.trim_blocks {
-                    let after = self.remaining();
-                    if after.starts_with("\r\n") { self.advance(2); }
-                    else if after.starts_with('\n') { self.advance(1); }
-                }
-                // A comment with a leading `-` ({#-) strips preceding whitespace from
-                // the already-emitted text — we cannot retroactively trim a previous
-                // token, but we can mark trim_next_start so the *following* text is
-                // trimmed, which is the practical effect for generation-prompt blocks.
-                if rest.starts_with("{#-") {
+                self.skip_block_newline();
+                // A trailing `-` (-#}) strips the whitespace after the comment.
+                if close > 2 && rest[..close].ends_with('-') {
                     self.trim_next_start = true;
                 }
-                return self.next_token(); // skip: recurse to get the next real token
+                return self.scan(); // skip: recurse to get the next real token
             }
 
             // Find first {{ or {%  (also matches {{- and {%-)
@@ -126,7 +225,10 @@ impl<'a> Tokenizer<'a> {
                 Some(0) => {
                     // We are sitting right at the tag opener — re-enter to handle {#
                     if rest.starts_with("{#") {
-                        return self.next_token();
+                        return self.scan();
+                    }
+                    if let Some((open_len, trim_start)) = raw_tag(rest, "raw") {
+                        return self.scan_raw(open_len, trim_start);
                     }
                     if rest.starts_with("{%-") {
                         self.advance(3);
@@ -154,6 +256,8 @@ impl<'a> Tokenizer<'a> {
                     // {#- strips trailing whitespace from the preceding text too
                     let text = if upcoming.starts_with("{%-") || upcoming.starts_with("{{-") || upcoming.starts_with("{#-") {
                         raw_text.trim_end().to_string()
+                    } else if self.lstrip_blocks && (upcoming.starts_with("{%") || upcoming.starts_with("{#")) {
+                        self.lstrip_line(raw_text).to_string()
                     } else {
                         raw_text.to_string()
                     };
@@ -170,7 +274,7 @@ impl<'a> Tokenizer<'a> {
                     if text.is_empty() {
                         // All whitespace consumed by trim — skip the empty token,
                         // and the next call will hit the {# or real tag at position 0
-                        self.next_token()
+                        self.scan()
                     } else {
                         Some(Token::Text(text))
                     }
@@ -200,6 +304,7 @@ impl<'a> Tokenizer<'a> {
             if rest.is_empty() {
                 return None;
             }
+            self.token_start = self.cursor;
 
             // Check tag ends — trim variants first
             if rest.starts_with("-%}") {
@@ -211,14 +316,7 @@ impl<'a> Tokenizer<'a> {
             if rest.starts_with("%}") {
                 self.advance(2);
                 self.in_tag = false;
-                if self.trim_blocks {
-                    let after = self.remaining();
-                    if after.starts_with("\r\n") {
-                        self.advance(2);
-                    } else if after.starts_with('\n') {
-                        self.advance(1);
-                    }
-                }
+                self.skip_block_newline();
                 return Some(Token::BlockEnd);
             }
             if rest.starts_with("-}}") {
@@ -313,47 +411,55 @@ impl<'a> Tokenizer<'a> {
 
             // Integer literals
             if first.is_ascii_digit() {
-                let int_str: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
-                self.advance(int_str.len());
-                let n: i64 = int_str.parse().unwrap_or(0);
+                let int_len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                // A `.` makes a float only when a digit follows, so `items[0].name`
+                // still reads as an index and an attribute.
+                let after = &rest[int_len..];
+                if after.starts_with('.') && after[1..].starts_with(|c: char| c.is_ascii_digit()) {
+                    let frac_len = after[1..].find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len() - 1);
+                    let float_str = &rest[..int_len + 1 + frac_len];
+                    self.advance(float_str.len());
+                    return Some(Token::FloatLit(float_str.parse().unwrap_or(0.0)));
+                }
+                // 2^63 only fits once negated; the parser rejects it anywhere
+                // but after a unary minus.
+                let digits = &rest[..int_len];
+                let n: i64 = match digits.parse() {
+                    Ok(n) => n,
+                    Err(_) if digits.parse::<u64>() == Ok(1 << 63) => i64::MIN,
+                    Err(_) => return self.fail(|pos| RenderError::IntOutOfRange { pos }, self.cursor),
+                };
+                self.advance(int_len);
                 return Some(Token::IntLit(n));
             }
 
             // String literals
             if first == '\'' || first == '"' {
                 let quote = first;
-                let mut end_idx = 1usize;
                 let mut s = String::new();
-                let mut chars = rest[1..].chars();
+                // Offsets from char_indices are relative to the text after the
+                // opening quote, so the closing quote's end is the bytes consumed.
+                let mut chars = rest[quote.len_utf8()..].char_indices();
                 loop {
                     match chars.next() {
-                        None => return None, // unterminated string
-                        Some(c) if c == quote => {
-                            self.advance(end_idx + quote.len_utf8());
+                        None => return self.fail(|pos| RenderError::UnterminatedString { pos }, self.cursor),
+                        Some((i, c)) if c == quote => {
+                            self.advance(quote.len_utf8() + i + c.len_utf8());
                             return Some(Token::StringLit(s));
                         }
-                        Some('\\') => {
-                            end_idx += 1;
-                            match chars.next() {
-                                None => return None,
-                                Some(esc) => {
-                                    end_idx += esc.len_utf8();
-                                    match esc {
-                                        'n'  => s.push('\n'),
-                                        't'  => s.push('\t'),
-                                        'r'  => s.push('\r'),
-                                        '\'' => s.push('\''),
-                                        '"'  => s.push('"'),
-                                        '\\' => s.push('\\'),
-                                        _    => s.push(esc),
-                                    }
-                                }
-                            }
-                        }
-                        Some(c) => {
-                            end_idx += c.len_utf8();
-                            s.push(c);
-                        }
+                        Some((_, '\\')) => match chars.next() {
+                            None => return self.fail(|pos| RenderError::UnterminatedString { pos }, self.cursor),
+                            Some((_, esc)) => match esc {
+                                'n'  => s.push('\n'),
+                                't'  => s.push('\t'),
+                                'r'  => s.push('\r'),
+                                '\'' => s.push('\''),
+                                '"'  => s.push('"'),
+                                '\\' => s.push('\\'),
+                                _    => s.push(esc),
+                            },
+                        },
+                        Some((_, c)) => s.push(c),
                     }
                 }
             }
@@ -386,7 +492,20 @@ impl<'a> Tokenizer<'a> {
 
             // Unknown character — skip and continue
             self.advance(1);
-            self.next_token()
+            self.scan()
         }
     }
 }
+
+/// If `s` starts with the block tag `{% name %}` (with optional `-` markers),
+/// return its length and whether it closes with `-%}`.
+fn raw_tag(s: &str, name: &str) -> Option<(usize, bool)> {
+    let inner = s.strip_prefix("{%")?;
+    let inner = inner.strip_prefix('-').unwrap_or(inner).trim_start();
+    let inner = inner.strip_prefix(name)?.trim_start();
+    let (after, trim) = match inner.strip_prefix("-%}") {
+        Some(after) => (after, true),
+        None => (inner.strip_prefix("%}")?, false),
+    };
+    Some((s.len() - after.len(), trim))
+}
@@ -1,16 +1,92 @@
 pub mod ast;
+pub mod error;
 pub mod eval;
 pub mod lexer;
 pub mod parser;
 
-use crate::eval::{Evaluator, Value};
+pub use crate::error::{Position, RenderError};
+pub use crate::eval::{Origin, OutputSegment, RenderInfo, Value};
+
+use crate::eval::Evaluator;
 use crate::parser::Parser;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ChatMessage {
     pub role: String,
-    pub content: String,
+    pub content: Content,
+    /// Function calls made by an assistant turn, usually maps shaped like
+    /// `{"type": "function", "function": {"name": ..., "arguments": ...}}`.
+    /// Exposed as `message.tool_calls` only when non-empty, so templates that
+    /// test `message.tool_calls is defined` see plain turns as having none.
+    pub tool_calls: Vec<Value>,
+    /// Additional fields exposed to templates as `message.<key>`, such as
+    /// `name` or `tool_call_id`. `role`, `content` and non-empty `tool_calls`
+    /// take precedence.
+    pub extra: HashMap<String, Value>,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<Content>) -> Self {
+        Self { role: role.into(), content: content.into(), tool_calls: Vec::new(), extra: HashMap::new() }
+    }
+
+    /// Attach tool calls to this message.
+    pub fn with_tool_calls(mut self, tool_calls: Vec<Value>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+
+    /// Add an extra field, e.g. `.with_extra("name", Value::String("get_weather".into()))`.
+    pub fn with_extra(mut self, key: impl Into<String>, value: Value) -> Self {
+        self.extra.insert(key.into(), value);
+        self
+    }
+}
+
+/// The `content` of a [`ChatMessage`].
+///
+/// Most messages are plain text. Multimodal messages carry a list of parts,
+/// usually maps like `{"type": "text", "text": "..."}` or `{"type": "image"}`,
+/// which templates see as `message.content` being a list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<Value>),
+}
+
+impl Content {
+    /// The text of a `Content::Text`, or `None` for multimodal parts.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Content::Text(s) => Some(s),
+            Content::Parts(_) => None,
+        }
+    }
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Content::Text(String::new())
+    }
+}
+
+impl From<String> for Content {
+    fn from(s: String) -> Self {
+        Content::Text(s)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(s: &str) -> Self {
+        Content::Text(s.to_string())
+    }
+}
+
+impl From<Vec<Value>> for Content {
+    fn from(parts: Vec<Value>) -> Self {
+        Content::Parts(parts)
+    }
 }
 
 /// Context variables available during template rendering.
@@ -18,17 +94,80 @@ pub struct ChatMessage {
 /// These map to the top-level Jinja context that HF's
 /// `tokenizer.apply_chat_template()` provides, such as `eos_token`,
 /// `bos_token`, `add_generation_prompt`, etc.
-#[derive(Debug, Clone, Default)]
+///
+/// [`new`](Self::new) starts with no variables or flags, so only what you set
+/// is in scope. [`Default`] presets the values [`render_chat_template`] uses:
+/// `eos_token = "</s>"` and `add_generation_prompt = true`.
+#[derive(Debug, Clone)]
 pub struct RenderContext {
     /// String variables (e.g., "eos_token" -> "</s>", "bos_token" -> "<s>")
     pub vars: HashMap<String, String>,
     /// Boolean variables (e.g., "add_generation_prompt" -> true)
     pub flags: HashMap<String, bool>,
+    /// Structured variables (e.g., "tools" -> a list of function schemas)
+    pub values: HashMap<String, Value>,
+    /// When true, reading an undefined variable, attribute or key is a render error
+    /// (Jinja2 `StrictUndefined`). Defaults to lenient (`Null`).
+    pub strict: bool,
+    /// Drop the first newline after a block tag (Jinja2 `trim_blocks`).
+    /// Defaults to true.
+    pub trim_blocks: bool,
+    /// Strip spaces and tabs from the start of a line up to a block tag
+    /// (Jinja2 `lstrip_blocks`). Defaults to false.
+    pub lstrip_blocks: bool,
+    /// When true, `==`, `!=`, `<`, `>`, `<=` and `>=` compare an integer with a
+    /// numeric string (`"5"`) by value, for configs that store numbers as
+    /// strings. Defaults to false: `5 == "5"` is false and `5 < "6"` an error.
+    pub coerce_numeric_strings: bool,
+    /// Unix timestamp (seconds, UTC) that `strftime_now()` reports instead of
+    /// the current local time. `None` reads the system clock.
+    pub now: Option<i64>,
+}
+
+impl Default for RenderContext {
+    /// The context [`render_chat_template`] renders with: `eos_token` is
+    /// `"</s>"` and `add_generation_prompt` is true. `bos_token` is not set.
+    fn default() -> Self {
+        let mut ctx = Self::new();
+        ctx.set_var("eos_token", "</s>");
+        ctx.set_flag("add_generation_prompt", true);
+        ctx
+    }
 }
 
 impl RenderContext {
+    /// An empty context: no variables, flags or values are preset.
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            vars: HashMap::new(),
+            flags: HashMap::new(),
+            values: HashMap::new(),
+            strict: false,
+            trim_blocks: true,
+            lstrip_blocks: false,
+            coerce_numeric_strings: false,
+            now: None,
+        }
+    }
+
+    /// Build a context from a JSON object, such as a model's tokenizer config.
+    ///
+    /// Strings become variables, booleans become flags and everything else is
+    /// converted to a structured [`Value`]. Anything other than an object
+    /// yields an empty context.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &serde_json::Value) -> Self {
+        let mut ctx = Self::new();
+        if let serde_json::Value::Object(fields) = json {
+            for (key, value) in fields {
+                match value {
+                    serde_json::Value::String(s) => ctx.set_var(key.as_str(), s.as_str()),
+                    serde_json::Value::Bool(b) => ctx.set_flag(key.as_str(), *b),
+                    other => ctx.set_value(key.as_str(), Value::from(other.clone())),
+                };
+            }
+        }
+        ctx
     }
 
     /// Set a string variable in the context.
@@ -42,12 +181,89 @@ impl RenderContext {
         self.flags.insert(key.into(), value);
         self
     }
+
+    /// Set a structured variable in the context, such as the `tools` list.
+    pub fn set_value(&mut self, key: impl Into<String>, value: Value) -> &mut Self {
+        self.values.insert(key.into(), value);
+        self
+    }
+
+    /// Enable or disable strict undefined handling.
+    pub fn set_strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Enable or disable Jinja2's `trim_blocks` whitespace option.
+    pub fn set_trim_blocks(&mut self, trim_blocks: bool) -> &mut Self {
+        self.trim_blocks = trim_blocks;
+        self
+    }
+
+    /// Enable or disable Jinja2's `lstrip_blocks` whitespace option.
+    pub fn set_lstrip_blocks(&mut self, lstrip_blocks: bool) -> &mut Self {
+        self.lstrip_blocks = lstrip_blocks;
+        self
+    }
+
+    /// Enable or disable numeric coercion of strings in comparisons with integers.
+    pub fn set_coerce_numeric_strings(&mut self, coerce: bool) -> &mut Self {
+        self.coerce_numeric_strings = coerce;
+        self
+    }
+
+    /// Pin the clock read by `strftime_now()` to a Unix timestamp, formatted
+    /// as UTC, so rendered dates are reproducible.
+    pub fn set_now(&mut self, timestamp: i64) -> &mut Self {
+        self.now = Some(timestamp);
+        self
+    }
+
+    /// Remove a variable, flag or value from the context, whichever kind it is.
+    pub fn unset(&mut self, key: &str) -> &mut Self {
+        self.vars.remove(key);
+        self.flags.remove(key);
+        self.values.remove(key);
+        self
+    }
+}
+
+/// Keep only the most recent `n` non-system messages.
+///
+/// When `keep_system` is true and the conversation starts with a `system`
+/// message, that message is kept in front of the retained turns. Any other
+/// system messages are treated like ordinary turns.
+pub fn cap_messages(messages: &[ChatMessage], n: usize, keep_system: bool) -> Vec<ChatMessage> {
+    let (system, rest) = match messages.split_first() {
+        Some((first, rest)) if first.role == "system" => (Some(first), rest),
+        _ => (None, messages),
+    };
+    let start = rest.len().saturating_sub(n);
+    let mut capped = Vec::with_capacity(rest.len().min(n) + 1);
+    if keep_system {
+        capped.extend(system.cloned());
+    }
+    capped.extend_from_slice(&rest[start..]);
+    capped
+}
+
+/// Stable 64-bit hash of a template source, for use as a cache key.
+///
+/// Uses FNV-1a, so the value is identical across processes, platforms and
+/// crate versions — unlike `DefaultHasher`, whose output may change between
+/// Rust releases. It is not collision resistant; do not use it where an
+/// attacker could choose templates to force collisions.
+pub fn template_hash(template: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    template.bytes().fold(FNV_OFFSET, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
 }
 
 /// Render a HF-style chat_template with messages and default context.
 ///
-/// Default context: `eos_token = "</s>"`, `add_generation_prompt = true`.
-/// For custom context, use [`render_chat_template_with_context`].
+/// Uses [`RenderContext::default`]: `eos_token = "</s>"`,
+/// `add_generation_prompt = true`. For custom context, use
+/// [`render_chat_template_with_context`].
 ///
 /// Supported subset of Jinja2:
 /// - Loops: `{% for message in messages %}`
@@ -56,39 +272,226 @@ impl RenderContext {
 /// - Literals: Strings, Booleans
 /// - Operators: `==`, `+` (string concat), `and`, `or`
 /// - Context: `messages` (provided), plus any variables from `RenderContext`
+///
+/// # Panics
+///
+/// Panics if the template fails to parse or render, see
+/// [`render_chat_template_with_context`]. Use [`try_render_chat_template`] to
+/// get the error instead.
 pub fn render_chat_template(template: &str, messages: &[ChatMessage]) -> String {
-    let mut ctx = RenderContext::new();
-    ctx.set_var("eos_token", "</s>");
-    ctx.set_flag("add_generation_prompt", true);
-    render_chat_template_with_context(template, messages, &ctx)
+    render_chat_template_with_context(template, messages, &RenderContext::default())
 }
 
 /// Render a HF-style chat_template with messages and explicit context.
 ///
-/// The context provides string variables (`eos_token`, `bos_token`) and
-/// boolean flags (`add_generation_prompt`) that the template can reference.
+/// The context provides string variables (`eos_token`, `bos_token`), boolean
+/// flags (`add_generation_prompt`) and structured [`Value`]s (`tools`) that the
+/// template can reference.
+///
+/// # Panics
+///
+/// Panics with the [`RenderError`] message if the template fails to parse or
+/// render, for example on a syntax error, a `raise_exception()` call, an
+/// undefined variable in strict mode or a loop over a non-iterable value.
+/// Use [`try_render_chat_template`] to get the error instead.
 pub fn render_chat_template_with_context(
     template: &str,
     messages: &[ChatMessage],
     ctx: &RenderContext,
 ) -> String {
-    let mut parser = Parser::new(template);
-    let ast = match parser.parse() {
-        Ok(ast) => ast,
-        Err(e) => panic!("Template Parsing Error: {}", e),
-    };
+    match try_render_chat_template(template, messages, ctx) {
+        Ok(s) => s,
+        Err(e) => panic!("{}", e),
+    }
+}
 
-    let mut context = HashMap::new();
+/// Render a HF-style chat_template, returning an error instead of panicking.
+///
+/// Use this when templates come from untrusted or user-supplied sources and a
+/// malformed template should be reported rather than abort the process.
+pub fn try_render_chat_template(
+    template: &str,
+    messages: &[ChatMessage],
+    ctx: &RenderContext,
+) -> Result<String, RenderError> {
+    Template::compile_with(template, ctx)?.render(messages, ctx)
+}
+
+/// Render a HF-style chat_template with messages pulled from an iterator.
+///
+/// Messages are consumed one at a time when the template allows it and
+/// buffered otherwise; see [`Template::render_from_iter`] and
+/// [`Template::streams_messages`] for which templates stream.
+pub fn render_chat_template_iter<I>(template: &str, messages: I, ctx: &RenderContext) -> Result<String, RenderError>
+where
+    I: IntoIterator<Item = ChatMessage>,
+{
+    Template::compile_with(template, ctx)?.render_from_iter(messages, ctx)
+}
+
+/// Render a HF-style chat_template into segments tagged by [`Origin`].
+///
+/// Concatenating the segment texts gives the same string as
+/// [`try_render_chat_template`]. Useful for token masking or highlighting.
+pub fn render_segments(
+    template: &str,
+    messages: &[ChatMessage],
+    ctx: &RenderContext,
+) -> Result<Vec<OutputSegment>, RenderError> {
+    Template::compile_with(template, ctx)?.render_segments(messages, ctx)
+}
+
+/// Render a HF-style chat_template, also reporting [`RenderInfo`] such as
+/// whether the generation prompt was emitted.
+pub fn render_info(
+    template: &str,
+    messages: &[ChatMessage],
+    ctx: &RenderContext,
+) -> Result<(String, RenderInfo), RenderError> {
+    Template::compile_with(template, ctx)?.render_info(messages, ctx)
+}
+
+/// A parsed chat_template that can be rendered many times.
+///
+/// Compiling tokenizes and parses the template once; each call to
+/// [`Template::render`] only evaluates the stored AST.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: ast::Template,
+    single_pass: Option<usize>, // top-level loop that can stream `messages`
+}
+
+impl Template {
+    /// Parse a template string into a reusable `Template`.
+    pub fn compile(source: &str) -> Result<Self, RenderError> {
+        Self::compile_with(source, &RenderContext::new())
+    }
 
-    // Transform messages into Value::Array of Value::Map
-    let mut msgs_val = Vec::new();
-    for m in messages {
-        let mut map = HashMap::new();
-        map.insert("role".to_string(), Value::String(m.role.clone()));
-        map.insert("content".to_string(), Value::String(m.content.clone()));
-        msgs_val.push(Value::Map(map));
+    /// Parse a template using the whitespace options (`trim_blocks`,
+    /// `lstrip_blocks`) from `ctx`. They are fixed at compile time; the
+    /// context passed to [`Template::render`] only supplies variables.
+    pub fn compile_with(source: &str, ctx: &RenderContext) -> Result<Self, RenderError> {
+        let mut parser = Parser::with_options(source, ctx.trim_blocks, ctx.lstrip_blocks);
+        let nodes = parser.parse()?;
+        let single_pass = eval::single_pass_loop(&nodes, "messages");
+        Ok(Self { nodes, single_pass })
     }
-    context.insert("messages".to_string(), Value::Array(msgs_val));
+
+    /// The parsed template, for inspecting its structure without rendering.
+    /// Walk it with [`ast::walk_template`] and an [`ast::NodeVisitor`].
+    pub fn nodes(&self) -> &[ast::Node] {
+        &self.nodes
+    }
+
+    /// Names the template reads from its context, such as `messages`,
+    /// `eos_token` or `tools`. Loop targets, `loop` and names assigned with
+    /// `{% set %}` before use are local and left out, so the result is what a
+    /// caller has to provide. Calls like `raise_exception()` are not included.
+    pub fn variables_used(&self) -> HashSet<String> {
+        eval::variables_used(&self.nodes)
+    }
+
+    /// Render the compiled template with messages and explicit context.
+    pub fn render(&self, messages: &[ChatMessage], ctx: &RenderContext) -> Result<String, RenderError> {
+        self.render_values(messages_to_values(messages), ctx)
+    }
+
+    /// Render the compiled template with messages already converted to `Value`s.
+    ///
+    /// Each message is normally a `Value::Map` with `role` and `content` keys,
+    /// but fields may hold any `Value` (e.g. integer or list content).
+    pub fn render_values(&self, messages: Vec<Value>, ctx: &RenderContext) -> Result<String, RenderError> {
+        let mut eval = evaluator(messages, ctx);
+        eval.render(&self.nodes).map_err(RenderError::Render)
+    }
+
+    /// Render the compiled template, also reporting [`RenderInfo`].
+    pub fn render_info(&self, messages: &[ChatMessage], ctx: &RenderContext) -> Result<(String, RenderInfo), RenderError> {
+        let mut eval = evaluator(messages_to_values(messages), ctx);
+        let output = eval.render(&self.nodes).map_err(RenderError::Render)?;
+        Ok((output, eval.info().clone()))
+    }
+
+    /// Render the compiled template incrementally into `w`; see
+    /// [`Evaluator::render_to`] for how errors are reported.
+    pub fn render_to<W: std::io::Write>(
+        &self,
+        messages: &[ChatMessage],
+        ctx: &RenderContext,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        let mut eval = evaluator(messages_to_values(messages), ctx);
+        eval.render_to(&self.nodes, w)
+    }
+
+    /// Render messages pulled from an iterator.
+    ///
+    /// When the template reads `messages` exactly once, in a top-level
+    /// `{% for %}` that needs no look-ahead beyond `loop.last`, messages are
+    /// consumed one at a time as the loop runs. Otherwise they are collected
+    /// first and rendered as with [`Template::render`].
+    pub fn render_from_iter<I>(&self, messages: I, ctx: &RenderContext) -> Result<String, RenderError>
+    where
+        I: IntoIterator<Item = ChatMessage>,
+    {
+        let mut values = messages.into_iter().map(|m| message_to_value(&m));
+        match self.single_pass {
+            Some(loop_at) => {
+                let mut eval = evaluator(Vec::new(), ctx);
+                eval.render_streamed(&self.nodes, loop_at, &mut values).map_err(RenderError::Render)
+            }
+            None => self.render_values(values.collect(), ctx),
+        }
+    }
+
+    /// True if [`Template::render_from_iter`] streams messages rather than
+    /// collecting them.
+    pub fn streams_messages(&self) -> bool {
+        self.single_pass.is_some()
+    }
+
+    /// Render into segments tagged by [`Origin`]; see [`render_segments`].
+    pub fn render_segments(&self, messages: &[ChatMessage], ctx: &RenderContext) -> Result<Vec<OutputSegment>, RenderError> {
+        let mut eval = evaluator(messages_to_values(messages), ctx);
+        eval.render_segments(&self.nodes).map_err(RenderError::Render)
+    }
+}
+
+/// Transform messages into a list of `Value::Map`s with `role`, `content`
+/// (a string, or a list for multimodal parts), `tool_calls` and any extra fields.
+fn messages_to_values(messages: &[ChatMessage]) -> Vec<Value> {
+    messages.iter().map(message_to_value).collect()
+}
+
+fn message_to_value(m: &ChatMessage) -> Value {
+    let mut map: BTreeMap<String, Value> = m.extra.clone().into_iter().collect();
+    map.insert("role".to_string(), Value::String(m.role.clone()));
+    let content = match &m.content {
+        Content::Text(s) => Value::String(s.clone()),
+        Content::Parts(parts) => Value::Array(parts.clone()),
+    };
+    map.insert("content".to_string(), content);
+    if !m.tool_calls.is_empty() {
+        map.insert("tool_calls".to_string(), Value::Array(m.tool_calls.clone()));
+    }
+    Value::Map(map)
+}
+
+/// Create an evaluator over `messages` and `ctx`, honoring its options.
+fn evaluator(messages: Vec<Value>, ctx: &RenderContext) -> Evaluator {
+    let mut eval = Evaluator::new(build_context(messages, ctx));
+    eval.set_strict(ctx.strict);
+    eval.set_coerce_numeric_strings(ctx.coerce_numeric_strings);
+    if let Some(timestamp) = ctx.now {
+        eval.set_now(timestamp);
+    }
+    eval
+}
+
+/// Build the top-level evaluator scope: `messages` plus everything in `ctx`.
+fn build_context(messages: Vec<Value>, ctx: &RenderContext) -> HashMap<String, Value> {
+    let mut context = HashMap::new();
+    context.insert("messages".to_string(), Value::Array(messages));
 
     // Inject string variables from context
     for (k, v) in &ctx.vars {
@@ -100,9 +503,9 @@ pub fn render_chat_template_with_context(
         context.insert(k.clone(), Value::Bool(*v));
     }
 
-    let mut eval = Evaluator::new(context);
-    match eval.render(&ast) {
-        Ok(s) => s,
-        Err(e) => panic!("Render Error: {}", e),
+    // Inject structured values from context
+    for (k, v) in &ctx.values {
+        context.insert(k.clone(), v.clone());
     }
+    context
 }
@@ -1,56 +1,133 @@
 use crate::ast::*;
+use crate::error::{Position, RenderError};
 use crate::lexer::{Token, Tokenizer};
 use std::collections::VecDeque;
 
 pub struct Parser<'a> {
+    source: &'a str,
     lexer: Tokenizer<'a>,
-    buffer: VecDeque<Token>,
+    buffer: VecDeque<(Token, usize)>, // (token, byte offset of its start)
+    last_offset: usize,               // offset of the most recently consumed token
+    lex_error: Option<RenderError>,   // tokenizer failure; reported in place of EOF
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
+        Self::with_options(input, true, false)
+    }
+
+    /// Parse with the given `trim_blocks` / `lstrip_blocks` settings; see
+    /// [`Tokenizer::with_options`].
+    pub fn with_options(input: &'a str, trim_blocks: bool, lstrip_blocks: bool) -> Self {
         Self {
-            lexer: Tokenizer::new(input),
+            source: input,
+            lexer: Tokenizer::with_options(input, trim_blocks, lstrip_blocks),
             buffer: VecDeque::new(),
+            last_offset: 0,
+            lex_error: None,
+        }
+    }
+
+    fn next_spanned(&mut self) -> Option<(Token, usize)> {
+        match self.lexer.next_token() {
+            Ok(token) => Some((token?, self.lexer.token_start())),
+            Err(e) => {
+                self.lex_error.get_or_insert(e);
+                None
+            }
         }
     }
 
     fn peek(&mut self, n: usize) -> Option<&Token> {
         while self.buffer.len() <= n {
-            if let Some(token) = self.lexer.next_token() {
-                self.buffer.push_back(token);
+            if let Some(spanned) = self.next_spanned() {
+                self.buffer.push_back(spanned);
             } else {
                 return None;
             }
         }
-        self.buffer.get(n)
+        self.buffer.get(n).map(|(t, _)| t)
     }
 
     fn consume(&mut self) -> Option<Token> {
-        if self.buffer.is_empty() {
-            self.lexer.next_token()
-        } else {
-            self.buffer.pop_front()
+        let next = match self.buffer.pop_front() {
+            Some(spanned) => Some(spanned),
+            None => self.next_spanned(),
+        };
+        match next {
+            Some((token, offset)) => {
+                self.last_offset = offset;
+                Some(token)
+            }
+            None => {
+                self.last_offset = self.source.len();
+                None
+            }
         }
     }
 
-    fn expect(&mut self, token: Token) -> Result<(), String> {
+    fn position(&self, offset: usize) -> Position {
+        Position::from_offset(self.source, offset)
+    }
+
+    /// Build an error for the token just returned by `consume` (or EOF).
+    fn unexpected(&self, expected: impl Into<String>, found: Option<Token>) -> RenderError {
+        if let (Some(err), None) = (&self.lex_error, &found) {
+            return err.clone();
+        }
+        let pos = self.position(self.last_offset);
+        match found {
+            Some(t) => RenderError::UnexpectedToken {
+                expected: expected.into(),
+                found: format!("{:?}", t),
+                pos,
+            },
+            None => RenderError::UnexpectedEof { expected: expected.into(), pos },
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), RenderError> {
         match self.consume() {
             Some(t) if t == token => Ok(()),
-            Some(t) => Err(format!("Expected {:?}, got {:?}", token, t)),
-            None => Err(format!("Expected {:?}, got EOF", token)),
+            t => Err(self.unexpected(format!("{:?}", token), t)),
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<Template, RenderError> {
+        let nodes = self.parse_body()?;
+        // The body stops at any block terminator; at the top level there is
+        // no open block for it to close.
+        if let Some(Token::BlockStart) = self.peek(0) {
+            self.consume(); // {%
+            let tag = self.consume();
+            return Err(self.misplaced(tag));
         }
+        Ok(nodes)
+    }
+
+    /// Error for a terminator tag just consumed where no open block accepts it.
+    fn misplaced(&self, tag: Option<Token>) -> RenderError {
+        let message = match tag {
+            Some(Token::Elif) => "elif without if".to_string(),
+            Some(Token::Else) => "else without if or for".to_string(),
+            Some(Token::EndIf) => "endif without if".to_string(),
+            Some(Token::EndFor) => "endfor without for".to_string(),
+            Some(Token::Ident(end)) if end.starts_with("end") => format!("{} without {}", end, &end[3..]),
+            other => return self.unexpected("block tag", other),
+        };
+        RenderError::MisplacedTag { message, pos: self.position(self.last_offset) }
     }
 
-    pub fn parse(&mut self) -> Result<Template, String> {
+    /// Parse nodes up to the next block terminator or the end of input.
+    fn parse_body(&mut self) -> Result<Template, RenderError> {
         let mut nodes = Vec::new();
         loop {
-            // Stop at block terminators (endfor, endif, else, elif)
+            // Stop at block terminators (endfor, endif, else, elif, endgeneration, endfilter, endset)
             if let Some(Token::BlockStart) = self.peek(0) {
-                if let Some(Token::EndFor | Token::EndIf | Token::Else | Token::Elif) =
-                    self.peek(1)
-                {
-                    break;
+                match self.peek(1) {
+                    Some(Token::EndFor | Token::EndIf | Token::Else | Token::Elif) => break,
+                    Some(Token::Ident(s)) if matches!(s.as_str(), "endgeneration" | "endfilter" | "endset") => break,
+                    _ => {}
                 }
             }
             if self.peek(0).is_none() {
@@ -74,44 +151,80 @@ impl<'a> Parser<'a> {
                         Some(Token::For) => nodes.push(self.parse_for()?),
                         Some(Token::If)  => nodes.push(self.parse_if()?),
                         Some(Token::Set) => nodes.push(self.parse_set()?),
-                        Some(t) => {
-                            let t = t.clone();
-                            return Err(format!("Unexpected tag inside block: {:?}", t));
+                        Some(Token::Ident(s)) if s == "generation" => {
+                            nodes.push(self.parse_generation()?)
+                        }
+                        Some(Token::Ident(s)) if s == "filter" => {
+                            nodes.push(self.parse_filter_block()?)
+                        }
+                        _ => {
+                            let t = self.consume();
+                            return Err(self.unexpected("block tag (for, if, set, generation, filter)", t));
                         }
-                        None => return Err("Unexpected EOF inside block start".to_string()),
                     }
                 }
                 _ => break,
             }
         }
+        if let Some(err) = &self.lex_error {
+            return Err(err.clone());
+        }
         Ok(nodes)
     }
 
-    fn parse_for(&mut self) -> Result<Node, String> {
+    fn parse_for(&mut self) -> Result<Node, RenderError> {
         self.expect(Token::For)?;
-        let target = match self.consume() {
-            Some(Token::Ident(s)) => s,
-            t => return Err(format!("Expected identifier for loop target, got {:?}", t)),
-        };
+        let mut targets = Vec::new();
+        loop {
+            match self.consume() {
+                Some(Token::Ident(s)) => targets.push(s),
+                t => return Err(self.unexpected("identifier for loop target", t)),
+            }
+            if let Some(Token::Comma) = self.peek(0) {
+                self.consume(); // ,
+            } else {
+                break;
+            }
+        }
         self.expect(Token::In)?;
-        let iterable = self.parse_expr()?;
+        // Parse below the ternary level so a trailing `if` is the loop filter,
+        // not an inline conditional.
+        let iterable = self.parse_or()?;
+        let filter = if let Some(Token::If) = self.peek(0) {
+            self.consume(); // if
+            Some(self.parse_or()?)
+        } else {
+            None
+        };
         self.expect(Token::BlockEnd)?;
 
-        let body = self.parse()?;
+        let body = self.parse_body()?;
+
+        let mut else_body = None;
+        if self.peek(0) == Some(&Token::BlockStart) && self.peek(1) == Some(&Token::Else) {
+            self.consume(); // {%
+            self.consume(); // else
+            self.expect(Token::BlockEnd)?;
+            else_body = Some(self.parse_body()?);
+        }
 
         self.expect(Token::BlockStart)?;
+        if let Some(Token::Elif) = self.peek(0) {
+            let tag = self.consume();
+            return Err(self.misplaced(tag));
+        }
         self.expect(Token::EndFor)?;
         self.expect(Token::BlockEnd)?;
 
-        Ok(Node::For { target, iterable, body })
+        Ok(Node::For { targets, iterable, filter, body, else_body })
     }
 
-    fn parse_if(&mut self) -> Result<Node, String> {
+    fn parse_if(&mut self) -> Result<Node, RenderError> {
         self.expect(Token::If)?;
         let condition = self.parse_expr()?;
         self.expect(Token::BlockEnd)?;
 
-        let body = self.parse()?;
+        let body = self.parse_body()?;
         let mut cases = vec![(condition, body)];
         let mut else_body = None;
 
@@ -123,15 +236,20 @@ impl<'a> Parser<'a> {
                         self.consume(); // elif
                         let cond = self.parse_expr()?;
                         self.expect(Token::BlockEnd)?;
-                        let block = self.parse()?;
+                        let block = self.parse_body()?;
                         cases.push((cond, block));
                     }
                     Some(Token::Else) => {
                         self.consume(); // {%
                         self.consume(); // else
                         self.expect(Token::BlockEnd)?;
-                        else_body = Some(self.parse()?);
+                        else_body = Some(self.parse_body()?);
                         self.expect(Token::BlockStart)?;
+                        if let Some(Token::Elif) = self.peek(0) {
+                            self.consume(); // elif
+                            let pos = self.position(self.last_offset);
+                            return Err(RenderError::MisplacedTag { message: "elif after else".to_string(), pos });
+                        }
                         self.expect(Token::EndIf)?;
                         self.expect(Token::BlockEnd)?;
                         break;
@@ -142,14 +260,15 @@ impl<'a> Parser<'a> {
                         self.expect(Token::BlockEnd)?;
                         break;
                     }
-                    t => return Err(format!("Expected elif, else, or endif, got {:?}", t)),
+                    _ => {
+                        self.consume(); // {%
+                        let t = self.consume();
+                        return Err(self.unexpected("elif, else, or endif", t));
+                    }
                 },
-                None => return Err("Unexpected EOF parsing if block".to_string()),
-                t => {
-                    return Err(format!(
-                        "Expected tag start for control flow, got {:?}",
-                        t
-                    ))
+                _ => {
+                    let t = self.consume();
+                    return Err(self.unexpected("elif, else, or endif", t));
                 }
             }
         }
@@ -157,27 +276,68 @@ impl<'a> Parser<'a> {
         Ok(Node::If { cases, else_body })
     }
 
-    fn parse_set(&mut self) -> Result<Node, String> {
+    fn parse_generation(&mut self) -> Result<Node, RenderError> {
+        self.expect(Token::Ident("generation".to_string()))?;
+        self.expect(Token::BlockEnd)?;
+        let body = self.parse_body()?;
+        self.expect(Token::BlockStart)?;
+        self.expect(Token::Ident("endgeneration".to_string()))?;
+        self.expect(Token::BlockEnd)?;
+        Ok(Node::Generation(body))
+    }
+
+    fn parse_filter_block(&mut self) -> Result<Node, RenderError> {
+        self.expect(Token::Ident("filter".to_string()))?;
+        let name = match self.consume() {
+            Some(Token::Ident(s)) => s,
+            t => return Err(self.unexpected("filter name after 'filter'", t)),
+        };
+        let args = if let Some(Token::LParen) = self.peek(0) {
+            self.consume(); // (
+            let a = self.parse_args()?;
+            self.expect(Token::RParen)?;
+            a
+        } else {
+            Vec::new()
+        };
+        self.expect(Token::BlockEnd)?;
+        let body = self.parse_body()?;
+        self.expect(Token::BlockStart)?;
+        self.expect(Token::Ident("endfilter".to_string()))?;
+        self.expect(Token::BlockEnd)?;
+        Ok(Node::FilterBlock { name, args, body })
+    }
+
+    fn parse_set(&mut self) -> Result<Node, RenderError> {
         self.expect(Token::Set)?;
         let base = match self.consume() {
             Some(Token::Ident(s)) => s,
-            t => return Err(format!("Expected identifier after 'set', got {:?}", t)),
+            t => return Err(self.unexpected("identifier after 'set'", t)),
         };
         // Handle dotted assignment: ns.foo = expr
-        // Parsed as flat key "ns.foo" — attribute gets discarded in eval (no-op for namespace).
+        // Kept as the dotted key "ns.foo"; the evaluator updates the field in place.
         let name = if let Some(Token::Dot) = self.peek(0) {
             let mut parts = vec![base];
             while let Some(Token::Dot) = self.peek(0) {
                 self.consume(); // .
                 match self.consume() {
                     Some(Token::Ident(s)) => parts.push(s),
-                    t => return Err(format!("Expected ident after '.' in set, got {:?}", t)),
+                    t => return Err(self.unexpected("identifier after '.' in set", t)),
                 }
             }
             parts.join(".")
         } else {
             base
         };
+        // Block capture form: {% set name %}...{% endset %}
+        if let Some(Token::BlockEnd) = self.peek(0) {
+            self.consume(); // %}
+            let body = self.parse_body()?;
+            self.expect(Token::BlockStart)?;
+            self.expect(Token::Ident("endset".to_string()))?;
+            self.expect(Token::BlockEnd)?;
+            return Ok(Node::SetBlock { name, body });
+        }
         self.expect(Token::Assign)?;
         let expr = self.parse_expr()?;
         self.expect(Token::BlockEnd)?;
@@ -186,17 +346,18 @@ impl<'a> Parser<'a> {
 
     // ── Expression grammar (lowest to highest precedence) ──────────────────
     //
-    //  expr         = or_expr
+    //  expr         = or_expr ['if' or_expr ['else' expr]]
     //  or_expr      = and_expr  ('or'  and_expr)*
     //  and_expr     = not_expr  ('and' not_expr)*
     //  not_expr     = 'not' not_expr  |  compare_expr
-    //  compare_expr = add_expr  (('==' | '!=' | 'is' ['not']) add_expr)*
+    //  compare_expr = add_expr  (('==' | '!=' | '<' | '>' | '<=' | '>=' | ['not'] 'in' | 'is' ['not']) add_expr)*
     //  add_expr     = mul_expr  ('+' mul_expr)*
     //  mul_expr     = postfix   ('%' postfix)*
     //  postfix      = base  ('.' IDENT | '[' (expr | slice) ']' | '|' IDENT ['(' args ')'])*
     //  base         = STRING | INT | BOOL | IDENT ['(' args ')'] | '(' expr ')' | '-' INT
+    //               | '[' [expr (',' expr)* [',']] ']'
 
-    fn parse_expr(&mut self) -> Result<Expr, String> {
+    fn parse_expr(&mut self) -> Result<Expr, RenderError> {
         let val = self.parse_or()?;
         // Inline ternary: `val if cond else fallback`
         if let Some(Token::If) = self.peek(0) {
@@ -204,7 +365,7 @@ impl<'a> Parser<'a> {
             let cond = self.parse_or()?;
             let else_val = if let Some(Token::Else) = self.peek(0) {
                 self.consume(); // else
-                self.parse_or()?
+                self.parse_expr()? // right-associative: `a if x else b if y else c`
             } else {
                 Expr::StringLit(String::new()) // implicit empty string when no else
             };
@@ -214,7 +375,7 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_or(&mut self) -> Result<Expr, String> {
+    fn parse_or(&mut self) -> Result<Expr, RenderError> {
         let mut lhs = self.parse_and()?;
         while let Some(Token::Or) = self.peek(0) {
             self.consume();
@@ -224,7 +385,7 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
-    fn parse_and(&mut self) -> Result<Expr, String> {
+    fn parse_and(&mut self) -> Result<Expr, RenderError> {
         let mut lhs = self.parse_not()?;
         while let Some(Token::And) = self.peek(0) {
             self.consume();
@@ -234,7 +395,7 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
-    fn parse_not(&mut self) -> Result<Expr, String> {
+    fn parse_not(&mut self) -> Result<Expr, RenderError> {
         if let Some(Token::Not) = self.peek(0) {
             self.consume();
             let inner = self.parse_not()?; // right-associative
@@ -244,7 +405,7 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_compare(&mut self) -> Result<Expr, String> {
+    fn parse_compare(&mut self) -> Result<Expr, RenderError> {
         let mut lhs = self.parse_add()?;
         loop {
             // Check for 'not in' compound operator before the main match to avoid
@@ -287,7 +448,7 @@ impl<'a> Parser<'a> {
                         Some(Token::Ident(s)) => s,
                         Some(Token::False)    => "false".to_string(),
                         Some(Token::True)     => "true".to_string(),
-                        t => return Err(format!("Expected test name after 'is', got {:?}", t)),
+                        t => return Err(self.unexpected("test name after 'is'", t)),
                     };
                     lhs = Expr::IsTest(Box::new(lhs), negated, test_name);
                 }
@@ -317,7 +478,7 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
-    fn parse_add(&mut self) -> Result<Expr, String> {
+    fn parse_add(&mut self) -> Result<Expr, RenderError> {
         let mut lhs = self.parse_mul()?;
         loop {
             match self.peek(0) {
@@ -337,7 +498,7 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
-    fn parse_mul(&mut self) -> Result<Expr, String> {
+    fn parse_mul(&mut self) -> Result<Expr, RenderError> {
         let mut lhs = self.parse_postfix()?;
         while let Some(Token::Percent) = self.peek(0) {
             self.consume();
@@ -347,7 +508,7 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
-    fn parse_postfix(&mut self) -> Result<Expr, String> {
+    fn parse_postfix(&mut self) -> Result<Expr, RenderError> {
         let mut expr = self.parse_base()?;
         loop {
             match self.peek(0) {
@@ -366,7 +527,7 @@ impl<'a> Parser<'a> {
                                 expr = Expr::Attribute(Box::new(expr), attr);
                             }
                         }
-                        t => return Err(format!("Expected identifier after '.', got {:?}", t)),
+                        t => return Err(self.unexpected("identifier after '.'", t)),
                     }
                 }
                 Some(Token::LBracket) => {
@@ -400,13 +561,13 @@ impl<'a> Parser<'a> {
                         }
                     }
                 }
+                // Filters chain left to right, each wrapping the expression so far,
+                // so they bind tighter than `+` and comparisons
                 Some(Token::Pipe) => {
                     self.consume(); // |
                     let filter_name = match self.consume() {
                         Some(Token::Ident(s)) => s,
-                        t => {
-                            return Err(format!("Expected filter name after '|', got {:?}", t))
-                        }
+                        t => return Err(self.unexpected("filter name after '|'", t)),
                     };
                     let args = if let Some(Token::LParen) = self.peek(0) {
                         self.consume(); // (
@@ -424,15 +585,18 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
-    fn parse_base(&mut self) -> Result<Expr, String> {
+    fn parse_base(&mut self) -> Result<Expr, RenderError> {
         match self.consume() {
             Some(Token::StringLit(s)) => Ok(Expr::StringLit(s)),
+            Some(Token::IntLit(i64::MIN)) => Err(RenderError::IntOutOfRange { pos: self.position(self.last_offset) }),
             Some(Token::IntLit(n))    => Ok(Expr::IntLit(n)),
+            Some(Token::FloatLit(f))  => Ok(Expr::FloatLit(f)),
             Some(Token::Minus) => {
-                // Unary minus — only meaningful before an integer literal
+                // Unary minus — only meaningful before a number literal
                 match self.consume() {
-                    Some(Token::IntLit(n)) => Ok(Expr::IntLit(-n)),
-                    t => Err(format!("Expected integer after unary '-', got {:?}", t)),
+                    Some(Token::IntLit(n)) => Ok(Expr::IntLit(n.wrapping_neg())),
+                    Some(Token::FloatLit(f)) => Ok(Expr::FloatLit(-f)),
+                    t => Err(self.unexpected("number after unary '-'", t)),
                 }
             }
             Some(Token::True)  => Ok(Expr::BoolLit(true)),
@@ -453,26 +617,43 @@ impl<'a> Parser<'a> {
                 self.expect(Token::RParen)?;
                 Ok(e)
             }
-            t => Err(format!("Expected expression, got {:?}", t)),
+            Some(Token::LBracket) => {
+                // List literal: [a, b, c] — empty and trailing comma allowed
+                let mut items = Vec::new();
+                while !matches!(self.peek(0), Some(Token::RBracket)) {
+                    items.push(self.parse_expr()?);
+                    if let Some(Token::Comma) = self.peek(0) {
+                        self.consume(); // ,
+                    } else {
+                        break;
+                    }
+                }
+                self.expect(Token::RBracket)?;
+                Ok(Expr::ListLit(items))
+            }
+            t => Err(self.unexpected("expression", t)),
         }
     }
 
     /// Parse a comma-separated argument list (stops before `)`).
-    /// Handles keyword arguments `name=value` by discarding the key and keeping the value.
-    fn parse_args(&mut self) -> Result<Vec<Expr>, String> {
+    /// Keyword arguments `name=value` keep their name so callees can match them.
+    fn parse_args(&mut self) -> Result<Vec<Arg>, RenderError> {
         let mut args = Vec::new();
         if let Some(Token::RParen) = self.peek(0) {
             return Ok(args);
         }
         loop {
-            // Keyword argument: ident = expr  → discard key, keep value
-            if matches!(self.peek(0), Some(Token::Ident(_)))
-                && matches!(self.peek(1), Some(Token::Assign))
-            {
-                self.consume(); // key name
-                self.consume(); // =
-            }
-            args.push(self.parse_expr()?);
+            // Keyword argument: ident = expr
+            let is_keyword = matches!(self.peek(0), Some(Token::Ident(_)))
+                && matches!(self.peek(1), Some(Token::Assign));
+            let name = match is_keyword.then(|| self.consume()).flatten() {
+                Some(Token::Ident(name)) => {
+                    self.consume(); // =
+                    Some(name)
+                }
+                _ => None,
+            };
+            args.push((name, self.parse_expr()?));
             if let Some(Token::Comma) = self.peek(0) {
                 self.consume(); // ,
                 if let Some(Token::RParen) = self.peek(0) {
@@ -1,4 +1,7 @@
-use shimmyjinja::{render_chat_template_with_context, ChatMessage, RenderContext};
+use shimmyjinja::{
+    cap_messages, render_chat_template, render_chat_template_iter, render_chat_template_with_context,
+    template_hash, try_render_chat_template, ChatMessage, RenderContext, RenderError, Template,
+};
 
 // ── Edge cases for crates.io publishing confidence ──
 
@@ -20,6 +23,16 @@ fn plain_text_template_no_tags() {
     assert_eq!(rendered, "Hello, world!");
 }
 
+#[test]
+fn leading_byte_order_mark_is_not_rendered() {
+    let template = "\u{feff}{% for message in messages %}{{ message.content }}{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[msg("user", "hi")], &RenderContext::new());
+    assert_eq!(rendered, "hi");
+    // Only a leading BOM is a byte order mark; elsewhere it is ordinary text.
+    let rendered = render_chat_template_with_context("\u{feff}a\u{feff}b", &[], &RenderContext::new());
+    assert_eq!(rendered, "a\u{feff}b");
+}
+
 #[test]
 fn context_var_outside_loop() {
     let template = "{{ bos_token }}PROMPT{{ eos_token }}";
@@ -36,7 +49,7 @@ fn dot_access_and_bracket_access_equivalent() {
     let template_dot = "{% for message in messages %}{{ message.role }}{% endfor %}";
     let template_bracket = "{% for message in messages %}{{ message['role'] }}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "user".to_string(), content: "hi".to_string() },
+        ChatMessage::new("user", "hi"),
     ];
     let ctx = RenderContext::new();
     let a = render_chat_template_with_context(template_dot, &messages, &ctx);
@@ -50,7 +63,7 @@ fn loop_first_and_last_single_message() {
     // With only one message, loop.first AND loop.last should both be true
     let template = "{% for message in messages %}{% if loop.first %}F{% endif %}{% if loop.last %}L{% endif %}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "user".to_string(), content: "x".to_string() },
+        ChatMessage::new("user", "x"),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
@@ -61,9 +74,9 @@ fn loop_first_and_last_single_message() {
 fn loop_first_and_last_multiple_messages() {
     let template = "{% for message in messages %}{% if loop.first %}[{% endif %}{{ message.role }}{% if loop.last %}]{% endif %}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "a".to_string(), content: "".to_string() },
-        ChatMessage { role: "b".to_string(), content: "".to_string() },
-        ChatMessage { role: "c".to_string(), content: "".to_string() },
+        ChatMessage::new("a", ""),
+        ChatMessage::new("b", ""),
+        ChatMessage::new("c", ""),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
@@ -74,9 +87,22 @@ fn loop_first_and_last_multiple_messages() {
 fn or_operator_in_condition() {
     let template = "{% for message in messages %}{% if message.role == 'user' or message.role == 'assistant' %}Y{% else %}N{% endif %}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "system".to_string(), content: "".to_string() },
-        ChatMessage { role: "user".to_string(), content: "".to_string() },
-        ChatMessage { role: "assistant".to_string(), content: "".to_string() },
+        ChatMessage::new("system", ""),
+        ChatMessage::new("user", ""),
+        ChatMessage::new("assistant", ""),
+    ];
+    let ctx = RenderContext::new();
+    let rendered = render_chat_template_with_context(template, &messages, &ctx);
+    assert_eq!(rendered, "NYY");
+}
+
+#[test]
+fn ne_operator_in_condition() {
+    let template = "{% for message in messages %}{% if message.role != 'system' %}Y{% else %}N{% endif %}{% endfor %}";
+    let messages = vec![
+        ChatMessage::new("system", ""),
+        ChatMessage::new("user", ""),
+        ChatMessage::new("assistant", ""),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
@@ -87,7 +113,7 @@ fn or_operator_in_condition() {
 fn string_concat_multiple_parts() {
     let template = "{% for message in messages %}{{ 'A' + 'B' + 'C' + message.role + 'D' }}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "x".to_string(), content: "".to_string() },
+        ChatMessage::new("x", ""),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
@@ -99,9 +125,9 @@ fn nested_if_inside_for() {
     // if inside if (via elif chain)
     let template = r#"{% for message in messages %}{% if message.role == 'user' %}U{% elif message.role == 'system' %}S{% else %}O{% endif %}{% endfor %}"#;
     let messages = vec![
-        ChatMessage { role: "user".to_string(), content: "".to_string() },
-        ChatMessage { role: "system".to_string(), content: "".to_string() },
-        ChatMessage { role: "tool".to_string(), content: "".to_string() },
+        ChatMessage::new("user", ""),
+        ChatMessage::new("system", ""),
+        ChatMessage::new("tool", ""),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
@@ -112,7 +138,7 @@ fn nested_if_inside_for() {
 fn special_characters_in_content() {
     let template = "{% for message in messages %}{{ message.content }}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "user".to_string(), content: "Hello <world> & \"friends\"".to_string() },
+        ChatMessage::new("user", "Hello <world> & \"friends\""),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
@@ -123,25 +149,64 @@ fn special_characters_in_content() {
 fn unicode_content() {
     let template = "{% for message in messages %}{{ message.content }}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "user".to_string(), content: "こんにちは 🌍".to_string() },
+        ChatMessage::new("user", "こんにちは 🌍"),
     ];
     let ctx = RenderContext::new();
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
     assert_eq!(rendered, "こんにちは 🌍");
 }
 
+#[test]
+fn string_literal_escapes_consume_exact_bytes() {
+    let template = r#"{{ 'a\\b' }}|{{ "say \"hi\"" }}|{{ 'it\'s' }}|{{ 'caf\é' + '\ü' }}|{{ '\日本' }}!"#;
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, r#"a\b|say "hi"|it's|caféü|日本!"#);
+}
+
 #[test]
 fn flag_default_false_when_missing() {
     // If add_generation_prompt is not in context at all, it should be falsy
     let template = "{% for message in messages %}{{ message.role }}{% if loop.last and add_generation_prompt %}PROMPT{% endif %}{% endfor %}";
     let messages = vec![
-        ChatMessage { role: "user".to_string(), content: "".to_string() },
+        ChatMessage::new("user", ""),
     ];
     let ctx = RenderContext::new(); // no flags set
     let rendered = render_chat_template_with_context(template, &messages, &ctx);
     assert_eq!(rendered, "user"); // no PROMPT appended
 }
 
+#[test]
+fn unset_removes_var_and_flag() {
+    let template = "{% if tools %}T{% else %}-{% endif %}{% if add_generation_prompt %}G{% else %}-{% endif %}";
+    let mut ctx = RenderContext::new();
+    ctx.set_var("tools", "[search]");
+    ctx.set_flag("add_generation_prompt", true);
+    assert_eq!(render_chat_template_with_context(template, &[], &ctx), "TG");
+
+    ctx.unset("tools").unset("add_generation_prompt");
+    assert_eq!(render_chat_template_with_context(template, &[], &ctx), "--");
+}
+
+#[test]
+fn new_context_is_empty() {
+    let ctx = RenderContext::new();
+    assert!(ctx.vars.is_empty() && ctx.flags.is_empty() && ctx.values.is_empty());
+}
+
+#[test]
+fn default_context_presets_documented_values() {
+    let ctx = RenderContext::default();
+    assert_eq!(ctx.vars.get("eos_token").map(String::as_str), Some("</s>"));
+    assert_eq!(ctx.flags.get("add_generation_prompt"), Some(&true));
+    assert_eq!(ctx.vars.get("bos_token"), None);
+    assert_eq!((ctx.vars.len(), ctx.flags.len(), ctx.values.len()), (1, 1, 0));
+
+    // render_chat_template renders with exactly these defaults
+    let template = "{{ bos_token }}|{{ eos_token }}|{{ add_generation_prompt }}";
+    assert_eq!(render_chat_template(template, &[]), "|</s>|True");
+    assert_eq!(render_chat_template_with_context(template, &[], &ctx), "|</s>|True");
+}
+
 // ── Jinja2 comment stripping ──────────────────────────────────────────────
 
 #[test]
@@ -166,6 +231,26 @@ fn comment_block_with_trim_modifier_stripped() {
     assert!(!out.contains("this is a comment"), "comment body must be stripped; got: {out:?}");
 }
 
+#[test]
+fn comment_with_trailing_trim_modifier_strips_following_whitespace() {
+    // -#} strips the whitespace after the comment, like -%} and -}}
+    let template = "a   {# c -#}   b";
+    let messages: Vec<ChatMessage> = vec![];
+    let ctx = RenderContext::new();
+    let out = render_chat_template_with_context(template, &messages, &ctx);
+    assert_eq!(out, "a   b");
+}
+
+#[test]
+fn comment_with_leading_trim_modifier_keeps_following_whitespace() {
+    // {#- strips only the whitespace before the comment
+    let template = "a  {#- c #}  b";
+    let messages: Vec<ChatMessage> = vec![];
+    let ctx = RenderContext::new();
+    let out = render_chat_template_with_context(template, &messages, &ctx);
+    assert_eq!(out, "a  b");
+}
+
 #[test]
 fn comment_between_tags_stripped() {
     // Realistic pattern from Llama 3.2: comment between two block tags
@@ -189,7 +274,7 @@ fn comment_in_llama32_style_template_does_not_leak() {
         "{{- system_message }}",
     );
     let messages = vec![
-        ChatMessage { role: "system".to_string(), content: "SYS".to_string() },
+        ChatMessage::new("system", "SYS"),
     ];
     let mut ctx = RenderContext::new();
     ctx.set_var("bos_token", "<BOS>");
@@ -200,3 +285,514 @@ fn comment_in_llama32_style_template_does_not_leak() {
     assert!(out.contains("SYS"), "system message not injected: {out:?}");
 }
 
+#[test]
+fn comment_only_template_renders_empty() {
+    let out = render_chat_template_with_context("{# nothing to see #}", &[], &RenderContext::new());
+    assert_eq!(out, "");
+}
+
+#[test]
+fn hash_outside_comment_is_literal_text() {
+    let template = "# Title {{ '#' }}{ # not a comment # }";
+    let out = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(out, "# Title #{ # not a comment # }");
+}
+
+#[test]
+fn unterminated_comment_is_lexer_error() {
+    let template = "ok\n  {# never closed {{ secret }}";
+    let err = try_render_chat_template(template, &[], &RenderContext::new()).unwrap_err();
+    assert!(matches!(err, RenderError::UnterminatedComment { .. }), "{err:?}");
+    let pos = err.position().unwrap();
+    assert_eq!((pos.line, pos.column), (2, 3));
+    assert_eq!(err.to_string(), "2:3: unterminated comment, expected '#}'");
+}
+
+#[test]
+fn unterminated_comment_inside_block_is_lexer_error() {
+    let template = "{% for m in messages %}{# oops {% endfor %}";
+    let err = Template::compile(template).unwrap_err();
+    assert!(matches!(err, RenderError::UnterminatedComment { .. }), "{err:?}");
+}
+
+#[test]
+fn unterminated_string_is_lexer_error() {
+    let err = Template::compile("Hi {{ 'oops }}").unwrap_err();
+    assert!(matches!(err, RenderError::UnterminatedString { .. }), "{err:?}");
+    assert_eq!(err.to_string(), "1:7: unterminated string literal at byte 6");
+}
+
+#[test]
+fn unterminated_string_after_trailing_backslash_is_lexer_error() {
+    let template = "{% if x %}\n{{ \"ends in backslash\\";
+    let err = try_render_chat_template(template, &[], &RenderContext::new()).unwrap_err();
+    assert!(matches!(err, RenderError::UnterminatedString { .. }), "{err:?}");
+    assert_eq!(err.position().map(|p| (p.line, p.column)), Some((2, 4)));
+}
+
+#[test]
+fn out_of_range_int_literal_is_lexer_error() {
+    let err = Template::compile("{{ 99999999999999999999 }}").unwrap_err();
+    assert!(matches!(err, RenderError::IntOutOfRange { .. }), "{err:?}");
+    assert_eq!(err.to_string(), "1:4: integer literal out of range");
+    let err = Template::compile("{{ 9223372036854775808 }}").unwrap_err();
+    assert!(matches!(err, RenderError::IntOutOfRange { .. }), "{err:?}");
+    assert_eq!(err.position().map(|p| p.column), Some(4));
+}
+
+#[test]
+fn int_literal_limits_round_trip() {
+    let template = "{{ 9223372036854775807 }}|{{ -9223372036854775808 }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "9223372036854775807|-9223372036854775808");
+}
+
+#[test]
+fn unterminated_raw_block_is_lexer_error() {
+    let template = "tools:\n  {% raw %}{{ not_a_var }}";
+    let err = Template::compile(template).unwrap_err();
+    assert!(matches!(err, RenderError::UnterminatedRaw { .. }), "{err:?}");
+    assert_eq!(err.to_string(), "2:3: unterminated raw block, expected '{% endraw %}'");
+}
+
+#[test]
+fn nested_message_loops_over_many_messages_render_small_output() {
+    // The buffer size hint once multiplied nested loops and summed dead
+    // branches, asking for hundreds of GB before rendering 4 bytes.
+    let template = format!(
+        "{{% for a in messages %}}{{% if loop.first %}}{{% for b in messages %}}{{% if false %}}{}{{% endif %}}{{% endfor %}}{{% endif %}}{{% endfor %}}done",
+        "x".repeat(1000)
+    );
+    let messages: Vec<ChatMessage> = (0..20_000).map(|_| msg("user", "")).collect();
+    let rendered = try_render_chat_template(&template, &messages, &RenderContext::new()).unwrap();
+    assert_eq!(rendered, "done");
+}
+
+#[test]
+fn compiled_template_renders_repeatedly() {
+    let template = Template::compile("{% for message in messages %}[{{ message.role }}]{{ message.content }}{% endfor %}")
+        .expect("template should compile");
+    let ctx = RenderContext::new();
+    let first = vec![
+        ChatMessage::new("user", "hi"),
+    ];
+    let second = vec![
+        ChatMessage::new("user", "a"),
+        ChatMessage::new("assistant", "b"),
+    ];
+    assert_eq!(template.render(&first, &ctx).unwrap(), "[user]hi");
+    assert_eq!(template.render(&second, &ctx).unwrap(), "[user]a[assistant]b");
+}
+
+#[test]
+fn compiled_template_matches_free_function() {
+    let source = "{{ bos_token }}{% for message in messages %}{{ message.content }}{% endfor %}";
+    let messages = vec![
+        ChatMessage::new("user", "x"),
+    ];
+    let mut ctx = RenderContext::new();
+    ctx.set_var("bos_token", "<s>");
+    let compiled = Template::compile(source).unwrap().render(&messages, &ctx).unwrap();
+    assert_eq!(compiled, render_chat_template_with_context(source, &messages, &ctx));
+}
+
+#[test]
+fn compile_reports_parse_error() {
+    assert!(Template::compile("{% for message in messages %}unterminated").is_err());
+}
+
+#[test]
+fn try_render_returns_ok_for_valid_template() {
+    let messages = vec![
+        ChatMessage::new("user", "hi"),
+    ];
+    let out = try_render_chat_template("{% for m in messages %}{{ m.content }}{% endfor %}", &messages, &RenderContext::new());
+    assert_eq!(out, Ok("hi".to_string()));
+}
+
+#[test]
+fn try_render_surfaces_parse_error() {
+    let err = try_render_chat_template("{% if x %}never closed", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.is_parse_error(), "expected parse error, got {err:?}");
+}
+
+#[test]
+fn try_render_surfaces_render_error() {
+    // Parses fine, but `+` between a string and a bool fails at evaluation time.
+    let err = try_render_chat_template("{{ 'a' + true }}", &[], &RenderContext::new()).unwrap_err();
+    assert!(matches!(err, RenderError::Render(_)), "expected render error, got {err:?}");
+    assert!(err.to_string().starts_with("Render Error:"));
+}
+
+#[test]
+fn parse_error_reports_line_and_column() {
+    let template = "line one\n{% for message in messages %}\n{{ message.content %}\n{% endfor %}";
+    let err = Template::compile(template).unwrap_err();
+    let pos = err.position().expect("parse errors carry a position");
+    assert_eq!((pos.line, pos.column), (3, 20), "{err}");
+    assert!(matches!(err, RenderError::UnexpectedToken { .. }), "{err:?}");
+    assert!(err.to_string().starts_with("3:20: expected VarEnd"), "{err}");
+}
+
+#[test]
+fn unexpected_eof_points_at_end_of_template() {
+    let template = "{% if x %}\nbody";
+    let err = Template::compile(template).unwrap_err();
+    assert!(matches!(err, RenderError::UnexpectedEof { .. }), "{err:?}");
+    assert_eq!(err.position().unwrap().offset, template.len());
+    assert_eq!(err.to_string(), "2:5: expected elif, else, or endif, found end of template");
+}
+
+#[test]
+fn elif_after_else_is_reported_at_the_elif() {
+    let template = "{% if a %}1{% else %}2{% elif b %}3{% endif %}";
+    let err = Template::compile(template).unwrap_err();
+    assert!(matches!(err, RenderError::MisplacedTag { .. }), "{err:?}");
+    assert_eq!(err.position().unwrap().offset, template.find("elif").unwrap());
+    assert_eq!(err.to_string(), "1:26: elif after else");
+}
+
+#[test]
+fn elif_without_if_is_reported_at_the_elif() {
+    for (template, shown) in [
+        ("Hello {% elif x %}world", "1:10: elif without if"),
+        ("{% for m in messages %}\n{% elif x %}{% endfor %}", "2:4: elif without if"),
+    ] {
+        let err = Template::compile(template).unwrap_err();
+        assert!(matches!(err, RenderError::MisplacedTag { .. }), "{err:?}");
+        assert_eq!(err.position().unwrap().offset, template.find("elif").unwrap());
+        assert_eq!(err.to_string(), shown);
+    }
+}
+
+#[test]
+fn stray_end_tags_no_longer_truncate_the_template() {
+    for (template, message) in [
+        ("a{% endif %}b", "endif without if"),
+        ("a{% endfor %}b", "endfor without for"),
+        ("a{% else %}b", "else without if or for"),
+        ("a{% endfilter %}b", "endfilter without filter"),
+    ] {
+        let err = Template::compile(template).unwrap_err();
+        assert_eq!(err.to_string(), format!("1:5: {}", message));
+    }
+}
+
+#[test]
+fn call_arguments_keep_keyword_names() {
+    use shimmyjinja::ast::{Expr, Node};
+    use shimmyjinja::parser::Parser;
+
+    let nodes = Parser::new("{{ f(1, x=2) }}").parse().unwrap();
+    let expected = Expr::Call(
+        "f".to_string(),
+        vec![(None, Expr::IntLit(1)), (Some("x".to_string()), Expr::IntLit(2))],
+    );
+    assert_eq!(nodes, vec![Node::Var(expected)]);
+}
+
+#[test]
+fn for_iterable_parses_as_an_expression() {
+    use shimmyjinja::ast::{Expr, Node};
+    use shimmyjinja::parser::Parser;
+
+    let nodes = Parser::new("{% for tc in message.tool_calls %}{% endfor %}").parse().unwrap();
+    let Node::For { iterable, .. } = &nodes[0] else { panic!("expected a for loop, got {:?}", nodes) };
+    assert_eq!(iterable, &Expr::Attribute(Box::new(Expr::Var("message".to_string())), "tool_calls".to_string()));
+}
+
+#[test]
+fn compiled_template_exposes_its_nodes() {
+    use shimmyjinja::ast::{walk_expr, walk_template, Expr, Node, NodeVisitor};
+
+    struct UsesVar<'a>(&'a str, bool);
+    impl NodeVisitor for UsesVar<'_> {
+        fn visit_expr(&mut self, expr: &Expr) {
+            self.1 |= matches!(expr, Expr::Var(name) if name == self.0);
+            walk_expr(self, expr);
+        }
+    }
+
+    let template = Template::compile(
+        "{% if tools %}{{ tools | tojson }}{% endif %}{% for message in messages %}{{ message.content }}{% endfor %}",
+    )
+    .unwrap();
+    let nodes = template.nodes();
+    assert_eq!(nodes.len(), 2);
+    assert!(nodes.iter().any(|node| matches!(node, Node::For { .. })));
+
+    let mut uses_tools = UsesVar("tools", false);
+    walk_template(nodes, &mut uses_tools);
+    assert!(uses_tools.1);
+}
+
+#[test]
+fn variables_used_excludes_loop_locals() {
+    let template = Template::compile(concat!(
+        "{{ bos_token }}{% set ns = namespace(seen=false) %}",
+        "{% for message in messages if message.role != skip_role %}",
+        "{% set text = message.content | trim %}",
+        "{{ loop.index }}{{ text }}{{ eos_token }}{% set ns.seen = true %}",
+        "{% else %}{{ fallback }}",
+        "{% endfor %}",
+        "{% if add_generation_prompt %}<|assistant|>{% endif %}{{ message }}"
+    ))
+    .unwrap();
+    let mut used: Vec<String> = template.variables_used().into_iter().collect();
+    used.sort();
+    assert_eq!(
+        used,
+        ["add_generation_prompt", "bos_token", "eos_token", "fallback", "message", "messages", "skip_role"]
+    );
+}
+
+#[test]
+fn filter_chain_nests_left_to_right() {
+    use shimmyjinja::ast::{BinOp, Expr, Node};
+    use shimmyjinja::parser::Parser;
+
+    let content = || Expr::Attribute(Box::new(Expr::Var("message".to_string())), "content".to_string());
+    let nodes = Parser::new("{{ message.content | trim | upper | truncate(20) }}").parse().unwrap();
+    let trimmed = Expr::Filter(Box::new(content()), "trim".to_string(), vec![]);
+    let upper = Expr::Filter(Box::new(trimmed), "upper".to_string(), vec![]);
+    let chain = Expr::Filter(Box::new(upper), "truncate".to_string(), vec![(None, Expr::IntLit(20))]);
+    assert_eq!(nodes, vec![Node::Var(chain)]);
+
+    // Looser than attribute access, tighter than `+` and comparisons.
+    let nodes = Parser::new("{{ 'a' + message.content | upper == 'aB' }}").parse().unwrap();
+    let upper = Expr::Filter(Box::new(content()), "upper".to_string(), vec![]);
+    let sum = Expr::BinOp(Box::new(Expr::StringLit("a".to_string())), BinOp::Add, Box::new(upper));
+    let cmp = Expr::BinOp(Box::new(sum), BinOp::Eq, Box::new(Expr::StringLit("aB".to_string())));
+    assert_eq!(nodes, vec![Node::Var(cmp)]);
+}
+
+#[test]
+fn three_filter_chain_applies_in_order() {
+    let template = "{{ messages[0].content | trim | upper | truncate(20) }}";
+    let messages = [msg("user", "   a long message that will be cut   ")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "A LONG MESSAGE...");
+}
+
+#[test]
+fn filters_accept_keyword_arguments() {
+    let template = concat!(
+        "{{ [1] | tojson(indent=2) }}|",
+        "{{ 'The quick brown fox' | truncate(12, end='~', leeway=0) }}|",
+        "{{ messages[0].get('name', default='anon') }}"
+    );
+    let rendered = render_chat_template_with_context(template, &[msg("user", "hi")], &RenderContext::new());
+    assert_eq!(rendered, "[\n  1\n]|The quick~|anon");
+}
+
+// ── cap_messages ──────────────────────────────────────────────────────────
+
+fn msg(role: &str, content: &str) -> ChatMessage {
+    ChatMessage::new(role, content)
+}
+
+#[test]
+fn cap_messages_keeps_leading_system_message() {
+    let messages = vec![
+        msg("system", "sys"),
+        msg("user", "u1"),
+        msg("assistant", "a1"),
+        msg("user", "u2"),
+        msg("assistant", "a2"),
+    ];
+    let capped = cap_messages(&messages, 2, true);
+    assert_eq!(capped, vec![msg("system", "sys"), msg("user", "u2"), msg("assistant", "a2")]);
+
+    let dropped = cap_messages(&messages, 2, false);
+    assert_eq!(dropped, vec![msg("user", "u2"), msg("assistant", "a2")]);
+}
+
+#[test]
+fn cap_messages_without_system_message() {
+    let messages = vec![msg("user", "u1"), msg("assistant", "a1"), msg("user", "u2")];
+    assert_eq!(cap_messages(&messages, 2, true), vec![msg("assistant", "a1"), msg("user", "u2")]);
+    assert_eq!(cap_messages(&messages, 10, true), messages);
+    assert!(cap_messages(&messages, 0, true).is_empty());
+}
+
+#[test]
+fn cap_messages_with_unbounded_limit_keeps_everything() {
+    let messages = vec![msg("system", "sys"), msg("user", "u1"), msg("assistant", "a1")];
+    assert_eq!(cap_messages(&messages, usize::MAX, true), messages);
+    assert_eq!(cap_messages(&messages, usize::MAX, false), messages[1..].to_vec());
+}
+
+#[test]
+fn trim_length_chain_on_large_content() {
+    let body = "word ".repeat(200_000);
+    let messages = vec![msg("user", &format!("\n\t  {}  \n", body))];
+    let out = render_chat_template_with_context(
+        "{% for message in messages %}{{ message.content | trim | length }}{% endfor %}",
+        &messages,
+        &RenderContext::new(),
+    );
+    assert_eq!(out, (body.len() - 1).to_string());
+}
+
+// ── render_from_iter ──────────────────────────────────────────────────────
+
+fn conversation() -> Vec<ChatMessage> {
+    vec![msg("system", "be brief"), msg("user", "hi"), msg("assistant", "hello"), msg("user", "bye")]
+}
+
+fn gen_ctx() -> RenderContext {
+    let mut ctx = RenderContext::new();
+    ctx.set_var("bos_token", "<s>");
+    ctx.set_flag("add_generation_prompt", true);
+    ctx
+}
+
+#[test]
+fn render_from_iter_streams_single_pass_template() {
+    let template = Template::compile(concat!(
+        "{{ bos_token }}",
+        "{% for message in messages %}",
+        "{{ loop.index }}:{{ message.role }}={{ message.content }}{% if not loop.last %}|{% endif %}",
+        "{% endfor %}",
+        "{% if add_generation_prompt %}>{% endif %}"
+    ))
+    .unwrap();
+    assert!(template.streams_messages());
+
+    let ctx = gen_ctx();
+    let streamed = template.render_from_iter(conversation(), &ctx).unwrap();
+    assert_eq!(streamed, template.render(&conversation(), &ctx).unwrap());
+    assert_eq!(streamed, "<s>1:system=be brief|2:user=hi|3:assistant=hello|4:user=bye>");
+}
+
+#[test]
+fn render_from_iter_collects_multi_access_template() {
+    let template = Template::compile(concat!(
+        "{% if messages[0].role == 'system' %}[{{ messages[0].content }}]{% endif %}",
+        "{% for message in messages %}{{ message.content }}/{{ loop.length }};{% endfor %}"
+    ))
+    .unwrap();
+    assert!(!template.streams_messages());
+
+    let ctx = gen_ctx();
+    let collected = template.render_from_iter(conversation(), &ctx).unwrap();
+    assert_eq!(collected, template.render(&conversation(), &ctx).unwrap());
+    assert_eq!(collected, "[be brief]be brief/4;hi/4;hello/4;bye/4;");
+}
+
+#[test]
+fn render_from_iter_collects_when_loop_needs_length_or_filter() {
+    let needs_length = Template::compile("{% for m in messages %}{{ loop.revindex }}{% endfor %}").unwrap();
+    let filtered = Template::compile("{% for m in messages if m.role == 'user' %}{{ m.content }}{% endfor %}").unwrap();
+    assert!(!needs_length.streams_messages());
+    assert!(!filtered.streams_messages());
+    assert_eq!(needs_length.render_from_iter(conversation(), &gen_ctx()).unwrap(), "4321");
+    assert_eq!(filtered.render_from_iter(conversation(), &gen_ctx()).unwrap(), "hibye");
+}
+
+#[test]
+fn render_from_iter_handles_empty_stream() {
+    let template = Template::compile("a{% for m in messages %}{{ loop.first }}{% endfor %}b").unwrap();
+    assert!(template.streams_messages());
+    assert_eq!(template.render_from_iter(std::iter::empty(), &RenderContext::new()).unwrap(), "ab");
+}
+
+#[test]
+fn render_from_iter_streams_for_else() {
+    let template = Template::compile("{% for m in messages %}{{ m.content }}{% else %}empty{% endfor %}").unwrap();
+    assert!(template.streams_messages());
+    assert_eq!(template.render_from_iter(std::iter::empty(), &RenderContext::new()).unwrap(), "empty");
+    assert_eq!(template.render_from_iter(vec![msg("user", "x")], &RenderContext::new()).unwrap(), "x");
+}
+
+#[test]
+fn render_from_iter_collects_when_messages_is_reassigned() {
+    let template = Template::compile("{% set messages = messages[1:] %}{% for m in messages %}{{ m.content }}{% endfor %}").unwrap();
+    assert!(!template.streams_messages());
+    assert_eq!(template.render_from_iter(conversation(), &gen_ctx()).unwrap(), "hihellobye");
+}
+
+#[test]
+fn render_chat_template_iter_accepts_iterator_adapters() {
+    let template = "{% for message in messages %}{{ message.role }}:{{ message.content }}{% if not loop.last %} {% endif %}{% endfor %}";
+    let lines = ["user hi", "assistant hello", "user bye"];
+    let messages = lines.iter().filter_map(|line| line.split_once(' ')).map(|(role, content)| msg(role, content));
+    let out = render_chat_template_iter(template, messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "user:hi assistant:hello user:bye");
+
+    // Arrays and Vecs work too, and a template needing `loop.length` still
+    // sees every message.
+    let template = "{% for message in messages %}{{ loop.index }}/{{ loop.length }} {% endfor %}";
+    let out = render_chat_template_iter(template, [msg("user", "a"), msg("user", "b")], &RenderContext::new());
+    assert_eq!(out.unwrap(), "1/2 2/2 ");
+    let out = render_chat_template_iter(template, conversation(), &RenderContext::new());
+    assert_eq!(out.unwrap(), "1/4 2/4 3/4 4/4 ");
+}
+
+// ── template_hash ─────────────────────────────────────────────────────────
+
+#[test]
+fn template_hash_is_stable_for_identical_strings() {
+    let source = "{% for message in messages %}{{ message.content }}{% endfor %}";
+    let copy = String::from(source);
+    assert_eq!(template_hash(source), template_hash(&copy));
+    // FNV-1a reference values, so keys survive restarts and upgrades.
+    assert_eq!(template_hash(""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(template_hash("a"), 0xaf63_dc4c_8601_ec8c);
+}
+
+#[test]
+fn template_hash_differs_for_different_strings() {
+    let a = "{{ bos_token }}{% for m in messages %}{{ m.content }}{% endfor %}";
+    let b = "{{ bos_token }}{% for m in messages %}{{ m.content }} {% endfor %}";
+    assert_ne!(template_hash(a), template_hash(b));
+    assert_ne!(template_hash("ab"), template_hash("ba"));
+}
+
+// ── render_to ─────────────────────────────────────────────────────────────
+
+const CHATML: &str = concat!(
+    "{{ bos_token }}{% for message in messages %}",
+    "<|im_start|>{{ message.role }}\n{{ message.content }}<|im_end|>\n",
+    "{% for i in range(loop.index) %}.{% endfor %}",
+    "{% endfor %}{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}"
+);
+
+#[test]
+fn render_to_writes_same_bytes_as_render() {
+    let template = Template::compile(CHATML).unwrap();
+    let mut buf = Vec::new();
+    template.render_to(&conversation(), &gen_ctx(), &mut buf).unwrap();
+    let expected = template.render(&conversation(), &gen_ctx()).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), expected);
+}
+
+#[test]
+fn render_to_reports_render_errors_as_invalid_data() {
+    let template = Template::compile("partial{{ raise_exception('bad role') }}").unwrap();
+    let mut buf = Vec::new();
+    let err = template.render_to(&[], &RenderContext::new(), &mut buf).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("bad role"), "{}", err);
+    // Output produced before the error has already been written.
+    assert_eq!(buf, b"partial");
+}
+
+#[test]
+fn render_to_returns_the_writer_error() {
+    struct Full;
+
+    impl std::io::Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "sink full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let template = Template::compile(CHATML).unwrap();
+    let err = template.render_to(&conversation(), &gen_ctx(), &mut Full).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::WriteZero);
+}
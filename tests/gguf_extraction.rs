@@ -11,7 +11,7 @@
 //! `SHIMMY_TEST_MODELS` environment variable).
 
 use shimmyjinja::{render_chat_template_with_context, ChatMessage, RenderContext};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
 // ── Minimal GGUF metadata reader ──────────────────────────────────────────
@@ -147,9 +147,9 @@ fn model_dir() -> PathBuf {
 
 fn two_turn_messages() -> Vec<ChatMessage> {
     vec![
-        ChatMessage { role: "user".into(),      content: "What is 2+2?".into() },
-        ChatMessage { role: "assistant".into(),  content: "4".into() },
-        ChatMessage { role: "user".into(),       content: "And 4+4?".into() },
+        ChatMessage::new("user", "What is 2+2?"),
+        ChatMessage::new("assistant", "4"),
+        ChatMessage::new("user", "And 4+4?"),
     ]
 }
 
@@ -292,7 +292,7 @@ fn gguf_llama32_structural_tokens_and_content() {
     ctx.set_flag("add_generation_prompt", true);
 
     let msgs = [
-        ChatMessage { role: "user".into(), content: "Hello there".into() },
+        ChatMessage::new("user", "Hello there"),
     ];
     let out = render_chat_template_with_context(&template, &msgs, &ctx);
 
@@ -322,10 +322,10 @@ fn gguf_llama32_with_system_message() {
     ctx.set_flag("add_generation_prompt", true);
 
     let msgs = [
-        ChatMessage { role: "system".into(),    content: "You are helpful.".into() },
-        ChatMessage { role: "user".into(),       content: "Hello".into() },
-        ChatMessage { role: "assistant".into(),  content: "Hi!".into() },
-        ChatMessage { role: "user".into(),       content: "Goodbye".into() },
+        ChatMessage::new("system", "You are helpful."),
+        ChatMessage::new("user", "Hello"),
+        ChatMessage::new("assistant", "Hi!"),
+        ChatMessage::new("user", "Goodbye"),
     ];
     let out = render_chat_template_with_context(&template, &msgs, &ctx);
 
@@ -356,7 +356,7 @@ fn gguf_qwen3_chatml_user_turn_without_tools() {
     // No tools → the short path through the template is taken.
 
     let msgs = [
-        ChatMessage { role: "user".into(), content: "Hello there".into() },
+        ChatMessage::new("user", "Hello there"),
     ];
     let out = render_chat_template_with_context(&template, &msgs, &ctx);
 
@@ -384,8 +384,8 @@ fn gguf_qwen3_system_message_then_user() {
     ctx.set_flag("add_generation_prompt", true);
 
     let msgs = [
-        ChatMessage { role: "system".into(), content: "Think carefully.".into() },
-        ChatMessage { role: "user".into(),   content: "What is 2+2?".into() },
+        ChatMessage::new("system", "Think carefully."),
+        ChatMessage::new("user", "What is 2+2?"),
     ];
     let out = render_chat_template_with_context(&template, &msgs, &ctx);
 
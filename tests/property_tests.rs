@@ -74,7 +74,7 @@ fn safe_role() -> impl Strategy<Value = String> {
 
 /// A single arbitrary ChatMessage with a canonical role and safe content.
 fn arb_message() -> impl Strategy<Value = ChatMessage> {
-    (safe_role(), safe_content()).prop_map(|(role, content)| ChatMessage { role, content })
+    (safe_role(), safe_content()).prop_map(|(role, content)| ChatMessage::new(role, content))
 }
 
 /// 1–8 messages (at least one so output is non-trivial).
@@ -130,7 +130,7 @@ proptest! {
         let out = render_chat_template_with_context(TMPL_CHATML, &messages, &ctx);
         for msg in &messages {
             prop_assert!(
-                out.contains(&msg.content),
+                out.contains(msg.content.as_text().unwrap()),
                 "content {:?} missing from output {:?}",
                 msg.content,
                 out
@@ -149,7 +149,7 @@ proptest! {
         let out = render_chat_template_with_context(TMPL_TINYLLAMA, &messages, &ctx);
         for msg in &messages {
             prop_assert!(
-                out.contains(&msg.content),
+                out.contains(msg.content.as_text().unwrap()),
                 "content {:?} missing from output {:?}",
                 msg.content,
                 out
@@ -293,7 +293,7 @@ proptest! {
         // Generate a single message with a long content string (1K–4K chars)
         content in prop::string::string_regex("[\\x20-\\x7a\\x7c-\\x7e]{1024,4096}").unwrap(),
     ) {
-        let messages = vec![ChatMessage { role: "user".into(), content: content.clone() }];
+        let messages = vec![ChatMessage::new("user", content.clone())];
         let ctx = make_ctx("", "</s>", false);
         let out = render_chat_template_with_context(TMPL_CHATML, &messages, &ctx);
         prop_assert!(
@@ -31,14 +31,14 @@
 //! Key consumed in `make_prompt_renderer()` inside `shimmy_server_gpu.rs`:
 //!   - `tokenizer.chat_template`  → Jinja2 template string
 //!   - `tokenizer.ggml.bos_token_id` → u32; passed to
-//!       `tokenizer.token_to_piece(id)` → bos_token string
+//!     `tokenizer.token_to_piece(id)` → bos_token string
 //!   - `tokenizer.ggml.eos_token_id` → u32; same → eos_token string
 //!
 //! All other architectural keys (`{arch}.embedding_length`, `block_count`,
 //! `attention.head_count`, …) are consumed by `spec.rs` / `BindlessMetadata`
 //! for tensor layout and are unrelated to the prompt template.
 
-use shimmyjinja::{render_chat_template_with_context, ChatMessage, RenderContext};
+use shimmyjinja::{render_chat_template_with_context, try_render_chat_template, ChatMessage, RenderContext};
 
 // ── Embedded template constants ───────────────────────────────────────────
 //
@@ -66,7 +66,7 @@ const TMPL_TINYLLAMA: &str = concat!(
 
 /// gemma-2-2b-it-Q4_K_M.gguf  (arch=gemma2, 591 chars)
 ///
-/// Features: `bos_token`, `raise_exception` (no-op), `loop.index0 % 2`,
+/// Features: `bos_token`, `raise_exception` guards, `loop.index0 % 2`,
 ///   `message['role'] == 'assistant'` → rename to `model`, `|trim` filter,
 ///   string concatenation with explicit delimiters.
 const TMPL_GEMMA2: &str = concat!(
@@ -160,15 +160,15 @@ const TMPL_DEEPSEEK: &str = concat!(
 // ── Shared helpers ────────────────────────────────────────────────────────
 
 fn user_msg(content: &str) -> ChatMessage {
-    ChatMessage { role: "user".into(), content: content.into() }
+    ChatMessage::new("user", content)
 }
 
 fn assistant_msg(content: &str) -> ChatMessage {
-    ChatMessage { role: "assistant".into(), content: content.into() }
+    ChatMessage::new("assistant", content)
 }
 
 fn system_msg(content: &str) -> ChatMessage {
-    ChatMessage { role: "system".into(), content: content.into() }
+    ChatMessage::new("system", content)
 }
 
 fn ctx_with(bos: &str, eos: &str, gen_prompt: bool) -> RenderContext {
@@ -275,18 +275,16 @@ fn gemma2_assistant_role_renamed_to_model() {
 }
 
 #[test]
-fn gemma2_system_role_causes_raise_exception_noop() {
-    // raise_exception() is a no-op in shimmyjinja (returns empty string).
-    // The template should still render the rest of the conversation.
+fn gemma2_system_role_raises_exception() {
+    // Gemma 2 has no system role; the template rejects it via raise_exception().
     let ctx = ctx_with("<bos>", "<eos>", true);
     let msgs = [
         system_msg("You are helpful."),
         user_msg("Hi"),
     ];
-    let out = render(TMPL_GEMMA2, &msgs, &ctx);
-    // Should not panic, and should still have user content
-    assert!(out.contains("<start_of_turn>"),
-        "render should continue after raise_exception noop; got: {out:?}");
+    let err = try_render_chat_template(TMPL_GEMMA2, &msgs, &ctx).unwrap_err();
+    assert!(err.to_string().contains("System role not supported"),
+        "expected raise_exception message; got: {err}");
 }
 
 #[test]
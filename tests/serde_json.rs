@@ -0,0 +1,86 @@
+//! Conversions from `serde_json::Value`. Run with `cargo test --features serde`.
+#![cfg(feature = "serde")]
+
+use serde_json::json;
+use shimmyjinja::eval::Value;
+use shimmyjinja::{RenderContext, Template};
+use std::collections::BTreeMap;
+
+#[test]
+fn scalars_convert() {
+    assert_eq!(Value::from(json!(null)), Value::Null);
+    assert_eq!(Value::from(json!(true)), Value::Bool(true));
+    assert_eq!(Value::from(json!(-7)), Value::Int(-7));
+    assert_eq!(Value::from(json!("hi")), Value::String("hi".into()));
+}
+
+#[test]
+fn fractional_numbers_convert_to_float() {
+    assert!(matches!(Value::from(json!(0.7)), Value::Float(f) if f == 0.7));
+    let out = Template::compile("{{ temperature }}|{{ temperature < 1 }}")
+        .unwrap()
+        .render(&[], &RenderContext::from_json(&json!({"temperature": 0.7})))
+        .unwrap();
+    assert_eq!(out, "0.7|True");
+}
+
+#[test]
+fn nested_structure_converts() {
+    let value = Value::from(json!({"name": "search", "tags": ["web", 1]}));
+    let mut expected = BTreeMap::new();
+    expected.insert("name".to_string(), Value::String("search".into()));
+    expected.insert(
+        "tags".to_string(),
+        Value::Array(vec![Value::String("web".into()), Value::Int(1)]),
+    );
+    assert_eq!(value, Value::Map(expected));
+}
+
+#[test]
+fn converted_messages_render_through_template() {
+    let messages = json!([
+        {"role": "user", "content": "What's the weather?"},
+        {"role": "assistant", "content": "", "tool_calls": [
+            {"function": {"name": "get_weather", "arguments": {"city": "Paris"}}}
+        ]}
+    ]);
+    let messages = match Value::from(messages) {
+        Value::Array(items) => items,
+        other => panic!("expected array, got {other:?}"),
+    };
+    let template = Template::compile(concat!(
+        "{% for message in messages %}",
+        "{{ message.role }}:{{ message.content }}",
+        "{% if message.tool_calls %}{{ message.tool_calls[0].function.name }}({{ message.tool_calls[0].function.arguments.city }}){% endif %};",
+        "{% endfor %}"
+    ))
+    .unwrap();
+    let out = template.render_values(messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "user:What's the weather?;assistant:get_weather(Paris);");
+}
+
+#[test]
+fn render_context_from_nested_json_object() {
+    let config = json!({
+        "bos_token": "<s>",
+        "add_generation_prompt": true,
+        "max_length": 2048,
+        "chat": {"roles": ["user", "assistant"], "separator": {"text": " | "}},
+        "unused": null
+    });
+    let ctx = RenderContext::from_json(&config);
+    assert_eq!(ctx.vars.get("bos_token").map(String::as_str), Some("<s>"));
+    assert_eq!(ctx.flags.get("add_generation_prompt"), Some(&true));
+    assert_eq!(ctx.values.get("max_length"), Some(&Value::Int(2048)));
+
+    let template = "{{ bos_token }}{{ chat.roles | join(chat.separator.text) }}:{{ max_length }}\
+{% if add_generation_prompt %}!{% endif %}";
+    let out = Template::compile(template).unwrap().render(&[], &ctx).unwrap();
+    assert_eq!(out, "<s>user | assistant:2048!");
+}
+
+#[test]
+fn render_context_from_non_object_json_is_empty() {
+    let ctx = RenderContext::from_json(&json!(["not", "an", "object"]));
+    assert!(ctx.vars.is_empty() && ctx.flags.is_empty() && ctx.values.is_empty());
+}
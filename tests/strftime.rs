@@ -0,0 +1,42 @@
+//! `strftime_now()` with a pinned clock. Run with `cargo test --features chrono`.
+#![cfg(feature = "chrono")]
+
+use shimmyjinja::{try_render_chat_template, ChatMessage, RenderContext};
+
+// 2024-07-23 14:05:09 UTC
+const PINNED: i64 = 1_721_743_509;
+
+fn pinned() -> RenderContext {
+    let mut ctx = RenderContext::new();
+    ctx.set_now(PINNED);
+    ctx
+}
+
+#[test]
+fn pinned_clock_formats_llama_date_header() {
+    let template = concat!(
+        "{% set date_string = strftime_now('%d %b %Y') %}",
+        "Cutting Knowledge Date: December 2023\nToday Date: {{ date_string }}\n\n",
+        "{% for message in messages %}{{ message.content }}{% endfor %}"
+    );
+    let out = try_render_chat_template(template, &[ChatMessage::new("user", "hi")], &pinned());
+    assert_eq!(out, Ok("Cutting Knowledge Date: December 2023\nToday Date: 23 Jul 2024\n\nhi".to_string()));
+}
+
+#[test]
+fn pinned_clock_includes_time_of_day() {
+    let out = try_render_chat_template("{{ strftime_now('%Y-%m-%dT%H:%M:%S') }}", &[], &pinned());
+    assert_eq!(out, Ok("2024-07-23T14:05:09".to_string()));
+}
+
+#[test]
+fn system_clock_is_used_without_a_pin() {
+    let out = try_render_chat_template("{{ strftime_now('%Y') }}", &[], &RenderContext::new()).unwrap();
+    assert!(out.len() == 4 && out.chars().all(|c| c.is_ascii_digit()), "{out}");
+}
+
+#[test]
+fn invalid_format_is_a_render_error() {
+    let err = try_render_chat_template("{{ strftime_now('%Q') }}", &[], &pinned()).unwrap_err();
+    assert!(err.to_string().contains("invalid format"), "{err}");
+}
@@ -0,0 +1,180 @@
+//! Strict (`StrictUndefined`-style) versus lenient handling of missing data.
+
+use shimmyjinja::{try_render_chat_template, ChatMessage, RenderContext, Template};
+
+fn user(content: &str) -> ChatMessage {
+    ChatMessage::new("user", content)
+}
+
+fn lenient() -> RenderContext {
+    RenderContext::new()
+}
+
+fn strict() -> RenderContext {
+    let mut c = RenderContext::new();
+    c.set_strict(true);
+    c
+}
+
+// ── missing attribute ──────────────────────────────────────────────────────
+
+const MISSING_ATTR: &str = "{% for message in messages %}[{{ message.name }}]{% endfor %}";
+
+#[test]
+fn missing_attribute_is_null_when_lenient() {
+    let out = try_render_chat_template(MISSING_ATTR, &[user("hi")], &lenient());
+    assert_eq!(out, Ok("[]".to_string()));
+}
+
+#[test]
+fn missing_attribute_errors_when_strict() {
+    let err = try_render_chat_template(MISSING_ATTR, &[user("hi")], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Attribute 'name' not found"), "{err}");
+}
+
+// ── missing key ────────────────────────────────────────────────────────────
+
+const MISSING_KEY: &str = "{% for message in messages %}[{{ message['tool_call_id'] }}]{% endfor %}";
+
+#[test]
+fn missing_key_is_null_when_lenient() {
+    let out = try_render_chat_template(MISSING_KEY, &[user("hi")], &lenient());
+    assert_eq!(out, Ok("[]".to_string()));
+}
+
+#[test]
+fn missing_key_errors_when_strict() {
+    let err = try_render_chat_template(MISSING_KEY, &[user("hi")], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Key 'tool_call_id' not found"), "{err}");
+}
+
+const MISSING_KEY_GUARD: &str = concat!(
+    "{% for message in messages %}",
+    "{% if message['tool_calls'] %}{{ message['tool_calls'][0]['id'] }}",
+    "{% elif message['tool_calls'][0] %}unreachable",
+    "{% else %}{{ message['content'] }}{% endif %}",
+    "{% endfor %}"
+);
+
+#[test]
+fn missing_key_guard_is_false_when_lenient() {
+    let out = try_render_chat_template(MISSING_KEY_GUARD, &[user("hi")], &lenient());
+    assert_eq!(out, Ok("hi".to_string()));
+}
+
+#[test]
+fn missing_key_guard_errors_when_strict() {
+    let err = try_render_chat_template(MISSING_KEY_GUARD, &[user("hi")], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Key 'tool_calls' not found"), "{err}");
+
+    let guarded = "{% for message in messages %}{% if message['tool_calls'] is defined %}x{% endif %}{% endfor %}";
+    assert_eq!(try_render_chat_template(guarded, &[user("hi")], &strict()), Ok(String::new()));
+}
+
+// ── undefined special token ────────────────────────────────────────────────
+
+const BOS_THEN_MESSAGES: &str = "{{ bos_token }}{% for message in messages %}{{ message.content }}{% endfor %}";
+
+#[test]
+fn undefined_bos_token_renders_empty_when_lenient() {
+    let out = try_render_chat_template(BOS_THEN_MESSAGES, &[user("hi")], &lenient());
+    assert_eq!(out, Ok("hi".to_string()));
+}
+
+#[test]
+fn undefined_bos_token_errors_when_strict() {
+    let err = try_render_chat_template(BOS_THEN_MESSAGES, &[user("hi")], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Variable 'bos_token' is undefined"), "{err}");
+}
+
+// ── misspelled variable ────────────────────────────────────────────────────
+
+const TYPO: &str = "{% for message in messages %}<{{ messsage.role }}>{{ message.content }}{% endfor %}";
+
+#[test]
+fn misspelled_variable_renders_empty_when_lenient() {
+    let out = try_render_chat_template(TYPO, &[user("hi")], &lenient());
+    assert_eq!(out, Ok("<>hi".to_string()));
+}
+
+#[test]
+fn misspelled_variable_errors_when_strict() {
+    let err = try_render_chat_template(TYPO, &[user("hi")], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Variable 'messsage' is undefined"), "{err}");
+    // The same check applies to conditions, not just output tags.
+    let err = try_render_chat_template("{% if add_generation_promt %}x{% endif %}", &[], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Variable 'add_generation_promt' is undefined"), "{err}");
+}
+
+#[test]
+fn strict_mode_defaults_to_off() {
+    assert!(!RenderContext::default().strict);
+}
+
+// ── probes that stay legal in strict mode ──────────────────────────────────
+
+#[test]
+fn strict_mode_allows_defined_default_and_get() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{{ message.name is defined }}|{{ message.name | default('anon') }}|{{ message.get('name', 'none') }}|{{ message.content }}",
+        "{% endfor %}"
+    );
+    let out = try_render_chat_template(template, &[user("hi")], &strict());
+    assert_eq!(out, Ok("False|anon|none|hi".to_string()));
+}
+
+#[test]
+fn strict_mode_allows_and_or_guards() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{% if message.tool_calls is defined and message.tool_calls %}calls{% endif %}",
+        "{% if tools is defined and tools %}tools{% endif %}",
+        "{% if message.tool_calls is not defined or message.tool_calls %}ok{% endif %}",
+        "{% endfor %}"
+    );
+    let out = try_render_chat_template(template, &[user("hi")], &strict());
+    assert_eq!(out, Ok("ok".to_string()));
+}
+
+#[test]
+fn strict_mode_still_checks_the_deciding_side() {
+    let err = try_render_chat_template("{% if true and tools %}x{% endif %}", &[], &strict()).unwrap_err();
+    assert!(err.to_string().contains("Variable 'tools' is undefined"), "{err}");
+}
+
+// ── non-iterable loop source ───────────────────────────────────────────────
+
+const SCALAR_LOOP: &str = "{% for x in add_generation_prompt %}[{{ x }}]{% else %}empty{% endfor %}|{{ add_generation_prompt is iterable }}";
+
+fn with_flag(mut ctx: RenderContext) -> RenderContext {
+    ctx.set_flag("add_generation_prompt", true);
+    ctx
+}
+
+#[test]
+fn scalar_loop_source_is_skipped_with_warning_when_lenient() {
+    let template = Template::compile(SCALAR_LOOP).unwrap();
+    let (out, info) = template.render_info(&[], &with_flag(lenient())).unwrap();
+    assert_eq!(out, "empty|False");
+    assert_eq!(info.warnings, vec!["Cannot iterate over Bool(true); loop skipped".to_string()]);
+}
+
+#[test]
+fn scalar_loop_source_errors_when_strict() {
+    let err = try_render_chat_template(SCALAR_LOOP, &[], &with_flag(strict())).unwrap_err();
+    assert!(err.to_string().contains("Cannot iterate over Bool(true)"), "{err}");
+}
+
+#[test]
+fn loop_and_is_iterable_agree_on_strings_lists_and_maps() {
+    let template = concat!(
+        "{% set sources = ['ab', [1, 2], messages[0], 3] %}",
+        "{% for s in sources %}{{ s is iterable }}:{% if s is iterable %}{% for x in s %}{{ x }}{% endfor %}{% endif %} {% endfor %}"
+    );
+    for ctx in [lenient(), strict()] {
+        // A message map iterates over its keys in sorted order.
+        let out = try_render_chat_template(template, &[user("hi")], &ctx);
+        assert_eq!(out, Ok("True:ab True:12 True:contentrole False: ".to_string()));
+    }
+}
@@ -1,18 +1,18 @@
 //! Integration tests using real Hugging Face chat_template strings.
 //! No model files are required — these tests run on raw Jinja strings only.
 
-use shimmyjinja::{render_chat_template_with_context, ChatMessage, RenderContext};
+use shimmyjinja::{render_chat_template_with_context, try_render_chat_template, ChatMessage, RenderContext};
 
 // ── helpers ────────────────────────────────────────────────────────────────
 
 fn user(content: &str) -> ChatMessage {
-    ChatMessage { role: "user".into(), content: content.into() }
+    ChatMessage::new("user", content)
 }
 fn assistant(content: &str) -> ChatMessage {
-    ChatMessage { role: "assistant".into(), content: content.into() }
+    ChatMessage::new("assistant", content)
 }
 fn system(content: &str) -> ChatMessage {
-    ChatMessage { role: "system".into(), content: content.into() }
+    ChatMessage::new("system", content)
 }
 
 fn ctx(bos: &str, eos: &str, add_gen: bool) -> RenderContext {
@@ -72,10 +72,7 @@ fn chatml_trim_filter_on_content() {
     );
 
     // Content has leading/trailing whitespace — | trim should strip it
-    let messages = vec![ChatMessage {
-        role: "user".into(),
-        content: "  hello world  ".into(),
-    }];
+    let messages = vec![ChatMessage::new("user", "  hello world  ")];
     let rendered = render_chat_template_with_context(template, &messages, &ctx("", "", false));
     assert!(rendered.contains("hello world<|im_end|>"), "trim should strip whitespace: {}", rendered);
     assert!(!rendered.contains("  hello"), "leading spaces should be gone");
@@ -173,10 +170,118 @@ fn set_statement_reassign_inside_loop() {
     assert!(rendered.contains("assistant: hello"), "assistant line: {}", rendered);
 }
 
+#[test]
+fn set_at_top_level_is_visible_inside_loop() {
+    let template = concat!(
+        "{% set sep = ' | ' %}",
+        "{% for message in messages %}{{ message.content }}{{ sep }}{% endfor %}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("a"), user("b")], &RenderContext::new());
+    assert_eq!(rendered, "a | b | ");
+}
+
+#[test]
+fn set_inside_if_is_visible_after_it() {
+    let template = "{% if messages %}{% set first = messages[0].content %}{% endif %}[{{ first }}]";
+    let rendered = render_chat_template_with_context(template, &[user("hi")], &RenderContext::new());
+    assert_eq!(rendered, "[hi]");
+}
+
+#[test]
+fn set_inside_loop_does_not_leak_out() {
+    // Each iteration gets its own scope, as in Jinja2.
+    let template = concat!(
+        "{% set last = 'none' %}",
+        "{% for message in messages %}{% set last = message.content %}{{ last }},{% endfor %}",
+        "{{ last }}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("a"), user("b")], &RenderContext::new());
+    assert_eq!(rendered, "a,b,none");
+}
+
+#[test]
+fn loop_and_target_are_private_to_the_loop() {
+    let template = concat!(
+        "{% set message = 'outer' %}",
+        "{% for message in messages %}{{ message.content }}{{ loop.index }},{% endfor %}",
+        "{{ message }}|{{ loop is defined }}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("a"), user("b")], &RenderContext::new());
+    assert_eq!(rendered, "a1,b2,outer|False");
+}
+
+#[test]
+fn nested_loop_shadows_and_restores_outer_loop() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{% for c in message.content %}{{ loop.index }}{% endfor %}",
+        "/{{ loop.index }};",
+        "{% endfor %}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("ab"), user("xyz")], &RenderContext::new());
+    assert_eq!(rendered, "12/1;123/2;");
+}
+
+#[test]
+fn loop_cycle_alternates_values() {
+    let template = "{% for message in messages %}<{{ loop.cycle('odd', 'even') }}:{{ message.content }}>{% endfor %}";
+    let messages = vec![user("a"), assistant("b"), user("c"), assistant("d")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "<odd:a><even:b><odd:c><even:d>");
+}
+
+#[test]
+fn loop_cycle_requires_values_and_a_loop() {
+    for template in ["{% for m in messages %}{{ loop.cycle() }}{% endfor %}", "{{ namespace().cycle('a') }}"] {
+        let err = try_render_chat_template(template, &[user("a")], &RenderContext::new()).unwrap_err();
+        assert!(err.to_string().contains("cycle()"), "{}: {}", template, err);
+    }
+}
+
+#[test]
+fn set_block_captures_loop_output() {
+    let template = concat!(
+        "{% set transcript %}",
+        "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}",
+        "{% endset %}",
+        "<<{{ transcript | trim }}>> ({{ transcript | length }} chars)"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("hi"), assistant("yo")], &RenderContext::new());
+    assert_eq!(rendered, "<<user: hi\nassistant: yo>> (23 chars)");
+}
+
+#[test]
+fn set_block_emits_nothing_in_place() {
+    let template = "a{% set x %}hidden{% endset %}b";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "ab");
+}
+
+#[test]
+fn unclosed_set_block_is_parse_error() {
+    let err = try_render_chat_template("{% set x %}abc", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.is_parse_error(), "{err}");
+}
+
+#[test]
+fn set_messages_slice_drops_system_before_loop() {
+    let template = concat!(
+        "{% if messages[0].role == 'system' %}",
+        "{% set system_message = messages[0].content %}",
+        "{% set messages = messages[1:] %}",
+        "{% endif %}",
+        "[{{ system_message }}]",
+        "{% for message in messages %}{{ message.role }}:{{ message.content }};{% endfor %}"
+    );
+    let messages = vec![system("S"), user("U"), assistant("A")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "[S]user:U;assistant:A;");
+}
+
 // ── Mistral ────────────────────────────────────────────────────────────────
 
 /// Simplified Mistral template — uses bos_token, eos_token, != comparison,
-/// raise_exception (no-op), elif
+/// raise_exception, elif
 #[test]
 fn mistral_basic() {
     let template = concat!(
@@ -203,9 +308,8 @@ fn mistral_basic() {
 }
 
 #[test]
-fn raise_exception_is_noop() {
-    // Templates sometimes call raise_exception in an else branch.
-    // It should produce no output, not crash.
+fn raise_exception_surfaces_as_error() {
+    // Templates call raise_exception in an else branch to reject bad input.
     let template = concat!(
         "{% for message in messages %}",
             "{% if message['role'] == 'user' %}",
@@ -216,7 +320,15 @@ fn raise_exception_is_noop() {
         "{% endfor %}"
     );
     let messages = vec![user("hello"), system("system prompt")];
-    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    let err = try_render_chat_template(template, &messages, &RenderContext::new()).unwrap_err();
+    assert!(!err.is_parse_error());
+    assert_eq!(err.to_string(), "Render Error: Unexpected role");
+}
+
+#[test]
+fn raise_exception_in_untaken_branch_is_inert() {
+    let template = "{% for message in messages %}{% if message.role != 'user' %}{{ raise_exception('bad') }}{% endif %}{{ message.content }}{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user("hello")], &RenderContext::new());
     assert_eq!(rendered, "hello");
 }
 
@@ -294,6 +406,90 @@ fn not_operator() {
     assert_eq!(rendered, "skip");
 }
 
+#[test]
+fn ternary_reads_add_generation_prompt_flag() {
+    let template = "{{ 'yes' if add_generation_prompt else 'no' }}";
+    assert_eq!(render_chat_template_with_context(template, &[], &ctx("", "", true)), "yes");
+    assert_eq!(render_chat_template_with_context(template, &[], &ctx("", "", false)), "no");
+    // An unset flag is undefined, which is falsy
+    assert_eq!(render_chat_template_with_context(template, &[], &RenderContext::new()), "no");
+}
+
+#[test]
+fn relational_operators_on_integers() {
+    let template = "{{ 1 < 2 }} {{ 3 >= 3 }} {{ 2 > 5 }} {{ 4 <= 3 }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "True True False False");
+}
+
+#[test]
+fn relational_operator_string_vs_int_is_error() {
+    let result = try_render_chat_template("{{ 'a' < 1 }}", &[], &RenderContext::new());
+    assert!(result.is_err(), "expected type error, got {:?}", result);
+}
+
+#[test]
+fn not_operator_on_literals_and_missing_vars() {
+    let template = "{{ not true }} {{ not '' }} {{ not missing_var }} {{ not not 'x' }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "False True True True");
+}
+
+#[test]
+fn not_binds_tighter_than_and() {
+    // Parsed as (not a) and b, not not (a and b)
+    let template = "{% if not a and b %}yes{% else %}no{% endif %}";
+    let mut c = RenderContext::new();
+    c.set_flag("a", false);
+    c.set_flag("b", false);
+    assert_eq!(render_chat_template_with_context(template, &[], &c), "no");
+    c.set_flag("b", true);
+    assert_eq!(render_chat_template_with_context(template, &[], &c), "yes");
+}
+
+#[test]
+fn list_literal_membership() {
+    let template = "{% for message in messages %}{% if message.role in ['user', 'assistant'] %}Y{% else %}N{% endif %}{% endfor %}";
+    let messages = vec![system("s"), user("u"), assistant("a")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "NYY");
+}
+
+#[test]
+fn list_literal_forms() {
+    let template = "{{ ['x', 'y'] }} {{ [] }} {{ [1, [2, 3],] }} {{ [['a'], []] | length }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, r#"["x", "y"] [] [1, [2, 3]] 2"#);
+}
+
+#[test]
+fn float_arithmetic_promotes_ints() {
+    let template = "{{ 1.5 + 2 }}|{{ 2 - 0.5 }}|{{ 0.1 + 0.2 }}|{{ 7.5 % 2 }}|{{ -0.25 + 1 }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "3.5|1.5|0.30000000000000004|1.5|0.75");
+}
+
+#[test]
+fn floats_render_without_padding() {
+    let template = "{{ 0.7 }}|{{ 2.0 }}|{{ 1.50 }}|{{ [0.7, 2.0] | tojson }}|{{ 2.7 | int }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "0.7|2.0|1.5|[0.7, 2.0]|2");
+}
+
+#[test]
+fn int_and_float_compare_numerically() {
+    let template = "{{ 2 == 2.0 }}|{{ 2 != 2.5 }}|{{ 0.5 < 1 }}|{{ 3 >= 3.0 }}|{{ 2.0 in [1, 2] }}|{{ 0.0 is number }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "True|True|True|True|True|True");
+}
+
+#[test]
+fn digits_before_attribute_access_stay_an_index() {
+    let template = "{{ messages[0].content }}{% for m in messages %}{{ loop.index0 }}.{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user("a"), user("b")], &RenderContext::new());
+    assert_eq!(rendered, "a0.1.");
+}
+
 // ── Filter tests ───────────────────────────────────────────────────────────
 
 #[test]
@@ -305,6 +501,28 @@ fn trim_filter_strips_whitespace() {
     assert_eq!(rendered, "hello");
 }
 
+#[test]
+fn escape_filter_replaces_html_specials() {
+    let template = "{% for message in messages %}{{ message.content | e }}|{{ message.content | escape }}{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user(r#"<a href="x">Tom & Jerry's</a>"#)], &RenderContext::new());
+    let escaped = "&lt;a href=&#34;x&#34;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;";
+    assert_eq!(rendered, format!("{}|{}", escaped, escaped));
+}
+
+#[test]
+fn escape_filter_stringifies_non_strings() {
+    let template = "{{ 3 | e }}|{{ ['<b>'] | e }}|{{ missing | e }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "3|[&#34;&lt;b&gt;&#34;]|");
+}
+
+#[test]
+fn safe_filter_passes_markup_through() {
+    let template = "{{ '<b>' | safe }}|{{ '<b>' }}|{{ messages[0].content | safe | length }}";
+    let rendered = render_chat_template_with_context(template, &[user("<i>")], &RenderContext::new());
+    assert_eq!(rendered, "<b>|<b>|3");
+}
+
 #[test]
 fn default_filter_on_null() {
     // 'missing' is not in context so it evaluates to Null → default kicks in
@@ -322,56 +540,937 @@ fn default_filter_on_present_value() {
     assert_eq!(rendered, "<|endoftext|>");
 }
 
-// ── Negative indexing ──────────────────────────────────────────────────────
+#[test]
+fn strip_think_removes_single_block() {
+    let template = "{{ value | strip_think }}";
+    let mut c = RenderContext::new();
+    c.set_var("value", "<think>plan the answer</think>The answer is 4.");
+    let rendered = render_chat_template_with_context(template, &[], &c);
+    assert_eq!(rendered, "The answer is 4.");
+}
 
 #[test]
-fn negative_array_index() {
-    // messages[-1] should get the last message
-    let template = "{{ messages[-1]['content'] }}";
-    let messages = vec![user("first"), user("last message")];
+fn strip_think_removes_multiple_blocks() {
+    let template = "{{ value | strip_think }}";
+    let mut c = RenderContext::new();
+    c.set_var("value", "<think>a</think>one <think>b</think>two");
+    let rendered = render_chat_template_with_context(template, &[], &c);
+    assert_eq!(rendered, "one two");
+}
+
+#[test]
+fn strip_think_leaves_unclosed_tag_intact() {
+    let template = "{{ value | strip_think }}";
+    let mut c = RenderContext::new();
+    c.set_var("value", "done <think>still reasoning");
+    let rendered = render_chat_template_with_context(template, &[], &c);
+    assert_eq!(rendered, "done <think>still reasoning");
+}
+
+#[test]
+fn strip_think_custom_tag_pair() {
+    let template = "{{ value | strip_think('<reasoning>', '</reasoning>') }}";
+    let mut c = RenderContext::new();
+    c.set_var("value", "<reasoning>hmm</reasoning>ok<think>kept</think>");
+    let rendered = render_chat_template_with_context(template, &[], &c);
+    assert_eq!(rendered, "ok<think>kept</think>");
+}
+
+#[test]
+fn format_filter_simple_default_template() {
+    let template = "{% for message in messages %}{{ '%s: %s' | format(message.role, message.content) }}\n{% endfor %}";
+    let messages = vec![system("Be brief."), user("Hi"), assistant("Hello!")];
     let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
-    assert_eq!(rendered, "last message");
+    assert_eq!(rendered, "system: Be brief.\nuser: Hi\nassistant: Hello!\n");
+
+    // Must match the plain interpolation form exactly.
+    let legacy = "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}";
+    assert_eq!(rendered, render_chat_template_with_context(legacy, &messages, &RenderContext::new()));
 }
 
 #[test]
-fn zero_index_access() {
-    let template = "{{ messages[0]['role'] }}";
-    let messages = vec![system("sys"), user("usr")];
+fn format_filter_integers_and_percent() {
+    let template = "{{ '%d%% of %s' | format(50, 'turns') }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "50% of turns");
+}
+
+#[test]
+fn split_then_first_extracts_first_line() {
+    let template = "{% for message in messages %}[{{ message.content.split('\\n') | first }}]{% endfor %}";
+    let messages = vec![user("Title line\nbody one\nbody two"), user("single line"), user("")];
     let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
-    assert_eq!(rendered, "system");
+    assert_eq!(rendered, "[Title line][single line][]");
 }
 
-// ── Whitespace control (`{%-` / `-%}`) ────────────────────────────────────
+#[test]
+fn split_without_separator_uses_whitespace() {
+    let template = "{{ value.split() | first }}|{{ value.split() | length }}";
+    let mut c = RenderContext::new();
+    c.set_var("value", "  alpha \t beta\ngamma ");
+    let rendered = render_chat_template_with_context(template, &[], &c);
+    assert_eq!(rendered, "alpha|3");
+}
 
 #[test]
-fn trim_block_start_strips_preceding_whitespace() {
-    // {%- strips trailing whitespace/newlines from preceding text
-    let template = "before   {%- if true %}inside{% endif %}";
+fn join_filter_with_separator() {
+    let template = "{{ ['a', 'b', 'c'] | join('-') }}|{{ [1, 2] | join }}|{{ [] | join(', ') }}";
     let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
-    assert_eq!(rendered, "beforeinside");
+    assert_eq!(rendered, "a-b-c|12|");
 }
 
 #[test]
-fn trim_block_end_strips_following_whitespace() {
-    // -%} strips leading whitespace/newlines from following text
-    let template = "{% if true -%}   after{% endif %}";
+fn join_filter_on_non_list_is_error() {
+    let result = try_render_chat_template("{{ 'abc' | join(',') }}", &[], &RenderContext::new());
+    assert!(result.is_err(), "expected error, got {:?}", result);
+}
+
+#[test]
+fn length_filter_on_sized_types() {
+    let template = "{{ messages | length }} {{ ['a', 'b', 'c'] | length }} {{ namespace() | length }} {{ 'héllo' | length }} {{ '日本語' | length }}";
+    let messages = vec![user("a"), assistant("b")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "2 3 0 5 3");
+}
+
+#[test]
+fn length_filter_on_map_counts_keys() {
+    let template = "{% for message in messages %}{{ message | length }}{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user("x")], &RenderContext::new());
+    assert_eq!(rendered, "2"); // role + content
+}
+
+#[test]
+fn length_filter_on_bool_is_error() {
+    let result = try_render_chat_template("{{ true | length }}", &[], &RenderContext::new());
+    assert!(result.is_err(), "expected error, got {:?}", result);
+}
+
+#[test]
+fn selectattr_list_length_counts_tool_messages() {
+    let template = "{{ messages | selectattr('role', 'equalto', 'tool') | list | length }}";
+    let messages = vec![
+        user("weather?"),
+        ChatMessage::new("tool", "{\"temp\": 20}"),
+        assistant("checking"),
+        ChatMessage::new("tool", "{\"rain\": false}"),
+        assistant("20 degrees, dry"),
+    ];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "2");
+}
+
+#[test]
+fn selectattr_chain_yields_integer() {
+    let template = concat!(
+        "{% set n = messages | selectattr('role', 'equalto', 'user') | list | length %}",
+        "{{ n is integer }}|{{ n + 1 }}|{{ n > 1 }}"
+    );
+    let messages = vec![user("a"), assistant("b"), user("c")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "True|3|True");
+}
+
+#[test]
+fn selectattr_eq_aliases_and_ne() {
+    let template = concat!(
+        "{% for m in messages | selectattr('role', 'eq', 'user') %}{{ m.content }}{% endfor %}|",
+        "{% for m in messages | selectattr('role', '==', 'user') %}{{ m.content }}{% endfor %}|",
+        "{% for m in messages | selectattr('role', 'ne', 'user') %}{{ m.content }}{% endfor %}"
+    );
+    let messages = vec![system("s"), user("u1"), assistant("a"), user("u2")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "u1u2|u1u2|sa");
+}
+
+#[test]
+fn selectattr_rejects_unknown_test() {
+    let err = try_render_chat_template("{{ messages | selectattr('role', 'matches', 'u') }}", &[], &RenderContext::new())
+        .unwrap_err();
+    assert!(err.to_string().contains("unsupported test 'matches'"), "{}", err);
+}
+
+#[test]
+fn selectattr_with_no_match_is_empty() {
+    let template = "{{ messages | selectattr('role', 'equalto', 'tool') | list | length }}";
+    let rendered = render_chat_template_with_context(template, &[user("a")], &RenderContext::new());
+    assert_eq!(rendered, "0");
+}
+
+#[test]
+fn ternary_picks_branch_per_message() {
+    let template = "{% for message in messages %}{{ 'assistant' if message.role == 'bot' else message.role }};{% endfor %}";
+    let messages = vec![user("u"), ChatMessage::new("bot", "b")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "user;assistant;");
+}
+
+#[test]
+fn ternary_else_branch_can_chain() {
+    let template = "{% for i in range(3) %}{{ 'zero' if i == 0 else 'one' if i == 1 else 'many' }} {% endfor %}";
     let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
-    assert_eq!(rendered, "after");
+    assert_eq!(rendered, "zero one many ");
 }
 
-// ── loop.index / loop.first / loop.last ───────────────────────────────────
+#[test]
+fn ternary_without_else_renders_empty() {
+    let template = "[{{ 'yes' if flag }}]";
+    assert_eq!(render_chat_template_with_context(template, &[], &RenderContext::new()), "[]");
+    let mut ctx = RenderContext::new();
+    ctx.set_flag("flag", true);
+    assert_eq!(render_chat_template_with_context(template, &[], &ctx), "[yes]");
+}
 
 #[test]
-fn loop_index0_is_integer() {
-    // loop.index0 == 0 should be truthy for the first iteration
+fn filter_binds_tighter_than_ternary() {
+    // Parses as (message.role | upper) if loop.first else message.role
+    let template = "{% for message in messages %}{{ message.role | upper if loop.first else message.role }} {% endfor %}";
+    let messages = vec![system("s"), user("u"), assistant("a")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "SYSTEM user assistant ");
+}
+
+#[test]
+fn collapse_whitespace_filter_on_expression() {
+    let template = "[{{ '  a   b\t\tc  ' | collapse_whitespace }}]";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "[a b c]");
+}
+
+#[test]
+fn collapse_whitespace_filter_block_joins_lines() {
     let template = concat!(
-        "{% for message in messages %}",
-            "{% if loop.index0 == 0 %}FIRST{% endif %}",
-            "{{ message['content'] }}",
-        "{% endfor %}"
+        "{% filter collapse_whitespace %}\n",
+        "  You are\n\n\n   a helpful\n",
+        "{% for message in messages %}  {{ message.content }}  \n{% endfor %}",
+        "{% endfilter %}|"
     );
-    let messages = vec![user("a"), user("b")];
+    let rendered = render_chat_template_with_context(template, &[user("assistant."), user("Be brief.")], &RenderContext::new());
+    assert_eq!(rendered, "You are a helpful assistant. Be brief.|");
+}
+
+#[test]
+fn filter_block_applies_any_filter_with_args() {
+    let template = "{% filter upper %}hi {{ 'there' }}{% endfilter %} {% filter default('x') %}{% endfilter %}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "HI THERE x");
+}
+
+#[test]
+fn unclosed_filter_block_is_parse_error() {
+    let err = try_render_chat_template("{% filter upper %}abc", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.is_parse_error(), "{err}");
+}
+
+#[test]
+fn wordwrap_paragraph_at_width_20() {
+    let template = "{{ 'The quick brown fox jumps over the lazy dog and keeps running far away' | wordwrap(20) }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "The quick brown fox\njumps over the lazy\ndog and keeps\nrunning far away");
+}
+
+#[test]
+fn wordwrap_keeps_blank_lines_between_paragraphs() {
+    let template = "{{ 'a\\n\\nb' | wordwrap(10) }}|{{ 'one two three\\n\\nfour' | wordwrap(7, true, '/') }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "a\n\nb|one two/three//four");
+}
+
+#[test]
+fn wordwrap_breaks_long_words_by_default() {
+    let template = "{{ 'short then averyveryverylongunbreakableword end' | wordwrap(20) }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "short then averyvery\nverylongunbreakablew\nord end");
+}
+
+#[test]
+fn wordwrap_keeps_long_words_when_disabled() {
+    let template = "{{ 'short then averyveryverylongunbreakableword end' | wordwrap(20, false) }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "short then\naveryveryverylongunbreakableword\nend");
+}
+
+#[test]
+fn wordwrap_rewraps_each_line_with_custom_wrapstring() {
+    let template = "{{ 'first line\\nsecond  line here is long' | wordwrap(10, true, '<br>') }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "first line<br>second<br>line here<br>is long");
+}
+
+#[test]
+fn truncate_cuts_at_word_boundary_with_leeway() {
+    let template = "{{ 'The quick brown fox jumps over the lazy dog' | truncate(20) }}|{{ 'twenty-two characters' | truncate(20) }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    // The second string is within the default leeway of 5, so it is kept.
+    assert_eq!(rendered, "The quick brown...|twenty-two characters");
+}
+
+#[test]
+fn truncate_killwords_and_custom_end() {
+    let template = "{{ 'The quick brown fox jumps over the lazy dog' | truncate(12, true, '~', 0) }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "The quick b~");
+}
+
+#[test]
+fn trim_truncate_default_chain() {
+    let template = "{% for message in messages %}[{{ message.content | trim | truncate(200) | default('...') }}]{% endfor %}";
+    let long = format!("  {}  ", "word ".repeat(60));
+    let messages = vec![user(&long), user(""), user("  \n "), user(" short ")];
     let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
-    assert!(rendered.contains("FIRSTa"), "first iter: {}", rendered);
-    assert!(!rendered.contains("FIRSTb"), "only first: {}", rendered);
+    let truncated = format!("{}...", vec!["word"; 39].join(" "));
+    assert_eq!(rendered, format!("[{}][...][...][short]", truncated));
+}
+
+#[test]
+fn replace_filter_substitutes_every_occurrence() {
+    let template = "{{ 'a-b-c-d' | replace('-', '+') }}|{{ 'a-b-c-d' | replace('-', '', 2) }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "a+b+c+d|abc-d");
+}
+
+#[test]
+fn replace_filter_flattens_newlines() {
+    let template = "{% for message in messages %}{{ message.content | replace('\\n', ' ') }}{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user("line one\nline two\n")], &RenderContext::new());
+    assert_eq!(rendered, "line one line two ");
+}
+
+#[test]
+fn replace_filter_rejects_non_string_input() {
+    let err = try_render_chat_template("{{ 42 | replace('4', '5') }}", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.to_string().contains("replace expects a string"), "{}", err);
+}
+
+#[test]
+fn capitalize_filter_on_role_labels() {
+    let template = "{% for message in messages %}{{ message.role | capitalize }}: {{ message.content | capitalize }}\n{% endfor %}";
+    let messages = vec![system("bE BRIEF"), user("éclair recipes please")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "System: Be brief\nUser: Éclair recipes please\n");
+}
+
+#[test]
+fn title_filter_capitalizes_each_word() {
+    let template = "{{ 'the QUICK brown-fox (über edition)' | title }}|{{ 'ñandú  salvaje' | title }}|{{ '' | title }}{{ '' | capitalize }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "The Quick Brown-Fox (Über Edition)|Ñandú  Salvaje|");
+}
+
+#[test]
+fn first_and_last_filters_on_messages() {
+    let template = "{{ (messages | first).role }}:{{ (messages | last).content }}";
+    let messages = vec![system("sys"), user("middle"), assistant("closing")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "system:closing");
+}
+
+#[test]
+fn first_and_last_filters_on_empty_list_are_none() {
+    let template = "{{ messages | first is none }}|{{ messages | last is none }}|[{{ messages | last }}]";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "True|True|[]");
+}
+
+#[test]
+fn first_and_last_filters_on_strings_use_chars() {
+    let template = "{{ 'héllo wörld' | first }}{{ 'héllo wörld' | last }}|{{ 'ñ' | last }}|[{{ '' | last }}]";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "hd|ñ|[]");
+}
+
+#[test]
+fn last_filter_rejects_non_indexable_values() {
+    let err = try_render_chat_template("{{ 7 | last }}", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.to_string().contains("last expects a list or string"), "{}", err);
+}
+
+#[test]
+fn int_filter_parses_strings() {
+    let template = "{{ '42' | int + 1 }}|{{ ' -7 ' | int }}|{{ '3.9' | int }}|{{ 5 | int }}|{{ true | int }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "43|-7|3|5|1");
+}
+
+#[test]
+fn int_filter_falls_back_on_parse_failure() {
+    let template = "{{ 'abc' | int }}|{{ 'abc' | int(-1) }}|{{ '' | int(default=9) }}|{{ missing | int }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "0|-1|9|0");
+}
+
+#[test]
+fn string_filter_enables_concatenation() {
+    let template = "{% for message in messages %}{{ 'turn ' + loop.index | string + ':' + message.role }} {% endfor %}|{{ true | string }}{{ ['a'] | string }}";
+    let rendered = render_chat_template_with_context(template, &[user("a"), assistant("b")], &RenderContext::new());
+    assert_eq!(rendered, "turn 1:user turn 2:assistant |True[\"a\"]");
+}
+
+#[test]
+fn startswith_and_endswith_methods() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{% if message.content.startswith('<tool>') %}T{% elif message.content.endswith('?') %}Q{% else %}-{% endif %}",
+        "{% endfor %}"
+    );
+    let messages = vec![user("<tool>call</tool>"), user("why?"), user("plain <tool>")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "TQ-");
+}
+
+#[test]
+fn startswith_rejects_non_strings() {
+    for template in ["{{ 'abc'.startswith(1) }}", "{{ messages.endswith('x') }}"] {
+        let err = try_render_chat_template(template, &[], &RenderContext::new()).unwrap_err();
+        assert!(err.to_string().contains("expects a string"), "{}: {}", template, err);
+    }
+}
+
+#[test]
+fn strip_methods_trim_whitespace() {
+    let template = "{% for message in messages %}[{{ message.content.strip() }}|{{ message.content.lstrip() }}|{{ message.content.rstrip() }}]{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user(" \n hi \t")], &RenderContext::new());
+    assert_eq!(rendered, "[hi|hi \t| \n hi]");
+}
+
+#[test]
+fn strip_methods_take_a_character_set() {
+    let template = "[{{ 'xx-hi-yx'.strip('xy') }}|{{ '--hi--'.lstrip('-') }}|{{ '--hi--'.rstrip('-') }}|{{ '  hi  '.strip(none) }}]";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "[-hi-|hi--|--hi|hi]");
+}
+
+// ── Negative indexing ──────────────────────────────────────────────────────
+
+#[test]
+fn negative_array_index() {
+    // messages[-1] should get the last message
+    let template = "{{ messages[-1]['content'] }}";
+    let messages = vec![user("first"), user("last message")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "last message");
+}
+
+#[test]
+fn zero_index_access() {
+    let template = "{{ messages[0]['role'] }}";
+    let messages = vec![system("sys"), user("usr")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "system");
+}
+
+#[test]
+fn integer_index_then_attribute() {
+    let template = "{{ messages[0].content }}|{{ messages[1]['content'] }}|{{ messages[0 + 1].role }}";
+    let messages = vec![system("sys"), user("usr")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "sys|usr|user");
+}
+
+#[test]
+fn computed_last_index_matches_negative_index() {
+    let messages = vec![system("sys"), user("first"), user("last message")];
+    let computed = "{{ messages[(messages | length) - 1]['content'] }}|{{ (messages | length) - 1 }}";
+    let negative = "{{ messages[-1]['content'] }}|{{ (messages | length) - 1 }}";
+    let rendered = render_chat_template_with_context(computed, &messages, &RenderContext::new());
+    assert_eq!(rendered, "last message|2");
+    assert_eq!(rendered, render_chat_template_with_context(negative, &messages, &RenderContext::new()));
+}
+
+#[test]
+fn negative_index_then_attribute() {
+    let template = "{{ messages[-1].role }}|{{ messages[-2].role }}";
+    let messages = vec![system("sys"), user("usr"), assistant("asst")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "assistant|user");
+}
+
+#[test]
+fn string_index_counts_chars_from_either_end() {
+    let template = "{{ messages[0].content[0] }}{{ messages[0].content[-1] }}{{ messages[0].content[1] }}";
+    let rendered = render_chat_template_with_context(template, &[user("héllo")], &RenderContext::new());
+    assert_eq!(rendered, "hoé");
+}
+
+#[test]
+fn out_of_bounds_index_is_an_error() {
+    for template in ["{{ messages[-3].role }}", "{{ messages[2].role }}", "{{ messages[0].content[-6] }}"] {
+        let err = try_render_chat_template(template, &[user("héllo"), user("x")], &RenderContext::new()).unwrap_err();
+        assert!(err.to_string().contains("out of bounds"), "{}: {}", template, err);
+    }
+}
+
+// ── Slicing ────────────────────────────────────────────────────────────────
+
+fn slice(template: &str, content: &str) -> String {
+    render_chat_template_with_context(template, &[user(content)], &RenderContext::new())
+}
+
+#[test]
+fn string_slice_start_and_end() {
+    assert_eq!(slice("{{ messages[0].content[0:5] }}", "hello world"), "hello");
+    assert_eq!(slice("{{ messages[0].content[6:11] }}", "hello world"), "world");
+}
+
+#[test]
+fn string_slice_end_only() {
+    assert_eq!(slice("{{ messages[0].content[:50] }}", "short"), "short");
+    assert_eq!(slice("{{ messages[0].content[:4] }}", "truncate me"), "trun");
+}
+
+#[test]
+fn string_slice_start_only() {
+    assert_eq!(slice("{{ messages[0].content[9:] }}", "truncate me"), "me");
+    assert_eq!(slice("{{ messages[0].content[-2:] }}", "truncate me"), "me");
+}
+
+#[test]
+fn string_slice_counts_chars_not_bytes() {
+    assert_eq!(slice("{{ messages[0].content[1:3] }}", "héllo"), "él");
+    assert_eq!(slice("{{ messages[0].content[:2] }}", "日本語"), "日本");
+}
+
+#[test]
+fn slice_bounds_clamp_instead_of_panicking() {
+    assert_eq!(slice("[{{ messages[0].content[5:100] }}]", "abc"), "[]");
+    assert_eq!(slice("[{{ messages[0].content[-100:2] }}]", "abc"), "[ab]");
+    assert_eq!(slice("[{{ messages[0].content[2:1] }}]", "abc"), "[]");
+    assert_eq!(slice("[{{ (messages[3:1] | length) }}{{ messages[-5:] | length }}]", "abc"), "[01]");
+}
+
+// ── Whitespace control (`{%-` / `-%}`) ────────────────────────────────────
+
+#[test]
+fn trim_block_start_strips_preceding_whitespace() {
+    // {%- strips trailing whitespace/newlines from preceding text
+    let template = "before   {%- if true %}inside{% endif %}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "beforeinside");
+}
+
+#[test]
+fn trim_block_end_strips_following_whitespace() {
+    // -%} strips leading whitespace/newlines from following text
+    let template = "{% if true -%}   after{% endif %}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "after");
+}
+
+#[test]
+fn trim_var_delimiters_strip_surrounding_newlines() {
+    let template = "a \n\t{{- 'x' -}}\n  b";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "axb");
+}
+
+#[test]
+fn trim_markers_only_affect_their_own_side() {
+    let template = "a  {{- 'x' }}  b  {{ 'y' -}}  c";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "ax  b  yc");
+}
+
+#[test]
+fn trim_markers_in_loop_collapse_indented_template() {
+    let template = concat!(
+        "{%- for message in messages -%}\n",
+        "    {{- message.role }}: {{ message.content }}\n",
+        "{%- endfor -%}\n",
+        "\nEND"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("hi"), assistant("yo")], &RenderContext::new());
+    assert_eq!(rendered, "user: hiassistant: yoEND");
+}
+
+#[test]
+fn trim_blocks_removes_single_newline_after_plain_block_end() {
+    // Without `-`, only the first newline after `%}` is dropped (trim_blocks);
+    // indentation on the next line is kept.
+    let template = "{% if true %}\n  x\n{% endif %}\ny";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "  x\ny");
+}
+
+#[test]
+fn trim_marker_and_trim_blocks_combine() {
+    // `-%}` strips all following whitespace, which subsumes trim_blocks.
+    let template = "{% if true -%}\n\n  x {%- endif %}\n\ny";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "x\ny");
+}
+
+// ── Raw blocks (`{% raw %}`) ───────────────────────────────────────────────
+
+#[test]
+fn raw_block_emits_tags_unrendered() {
+    let template = "{% set not_a_var = 'x' %}{% raw %}{{ not_a_var }} {% if %}{# kept #}{% endraw %}|{{ not_a_var }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "{{ not_a_var }} {% if %}{# kept #}|x");
+}
+
+#[test]
+fn raw_block_keeps_inner_newlines_under_trim_blocks() {
+    let template = "{% raw %}\n{\"type\": \"object\"}\n{% endraw %}\nend";
+    let rendered = render_chat_template_with_context(template, &[], &whitespace_ctx(true, false));
+    assert_eq!(rendered, "\n{\"type\": \"object\"}\nend");
+    let rendered = render_chat_template_with_context(template, &[], &whitespace_ctx(false, false));
+    assert_eq!(rendered, "\n{\"type\": \"object\"}\n\nend");
+}
+
+#[test]
+fn raw_block_honors_dash_markers() {
+    let template = "a {%- raw -%}  {{ x }}  {%- endraw -%} b";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "a{{ x }}b");
+}
+
+// ── trim_blocks / lstrip_blocks options ───────────────────────────────────
+
+const INDENTED_BLOCKS: &str = "<\n  {% if true %}\n  x\n  {% endif %}\n>";
+
+fn whitespace_ctx(trim_blocks: bool, lstrip_blocks: bool) -> RenderContext {
+    let mut c = RenderContext::new();
+    c.set_trim_blocks(trim_blocks).set_lstrip_blocks(lstrip_blocks);
+    c
+}
+
+#[test]
+fn whitespace_options_default_to_trim_only() {
+    let c = RenderContext::new();
+    assert!(c.trim_blocks);
+    assert!(!c.lstrip_blocks);
+}
+
+#[test]
+fn trim_blocks_on_lstrip_blocks_off() {
+    let rendered = render_chat_template_with_context(INDENTED_BLOCKS, &[], &whitespace_ctx(true, false));
+    assert_eq!(rendered, "<\n    x\n  >");
+}
+
+#[test]
+fn trim_blocks_off_keeps_newline_after_tag() {
+    let rendered = render_chat_template_with_context(INDENTED_BLOCKS, &[], &whitespace_ctx(false, false));
+    assert_eq!(rendered, "<\n  \n  x\n  \n>");
+}
+
+#[test]
+fn lstrip_blocks_strips_indentation_before_tag() {
+    let rendered = render_chat_template_with_context(INDENTED_BLOCKS, &[], &whitespace_ctx(false, true));
+    assert_eq!(rendered, "<\n\n  x\n\n>");
+}
+
+#[test]
+fn trim_and_lstrip_blocks_together() {
+    let rendered = render_chat_template_with_context(INDENTED_BLOCKS, &[], &whitespace_ctx(true, true));
+    assert_eq!(rendered, "<\n  x\n>");
+}
+
+#[test]
+fn lstrip_blocks_ignores_variable_tags_and_non_blank_prefixes() {
+    let template = "  {{ 'v' }}\n  a {% if true %}b{% endif %}";
+    let rendered = render_chat_template_with_context(template, &[], &whitespace_ctx(true, true));
+    assert_eq!(rendered, "  v\n  a b");
+}
+
+#[test]
+fn template_ending_at_endif_renders_both_branches() {
+    let template = "{% for message in messages %}{{ message.content }}\n{% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}";
+    for (add_gen, expected) in [(true, "hi\n<|assistant|>"), (false, "hi\n")] {
+        for trim_blocks in [true, false] {
+            let mut c = ctx("", "", add_gen);
+            c.set_trim_blocks(trim_blocks);
+            let rendered = render_chat_template_with_context(template, &[user("hi")], &c);
+            assert_eq!(rendered, expected, "add_gen={add_gen} trim_blocks={trim_blocks}");
+        }
+    }
+}
+
+#[test]
+fn template_ending_at_trimmed_endif_with_trailing_newline() {
+    for (flag, expected) in [(true, "yes"), (false, "")] {
+        let mut c = RenderContext::new();
+        c.set_flag("flag", flag);
+        assert_eq!(render_chat_template_with_context("{% if flag %}yes{% endif %}", &[], &c), expected);
+        assert_eq!(render_chat_template_with_context("{% if flag %}yes{% endif %}\n", &[], &c), expected);
+        assert_eq!(render_chat_template_with_context("{% if flag %}yes{% endif -%}\n\n", &[], &c), expected);
+    }
+}
+
+// ── loop.index / loop.first / loop.last ───────────────────────────────────
+
+#[test]
+fn loop_index0_is_integer() {
+    // loop.index0 == 0 should be truthy for the first iteration
+    let template = concat!(
+        "{% for message in messages %}",
+            "{% if loop.index0 == 0 %}FIRST{% endif %}",
+            "{{ message['content'] }}",
+        "{% endfor %}"
+    );
+    let messages = vec![user("a"), user("b")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert!(rendered.contains("FIRSTa"), "first iter: {}", rendered);
+    assert!(!rendered.contains("FIRSTb"), "only first: {}", rendered);
+}
+
+#[test]
+fn loop_index_is_one_based_integer() {
+    let template = "{% for message in messages %}{{ loop.index }}{% endfor %}";
+    let messages = vec![user("a"), user("b"), user("c")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "123");
+}
+
+#[test]
+fn loop_index_supports_arithmetic() {
+    let template = "{% for message in messages %}{{ loop.index0 + 10 }},{{ loop.index - 1 }};{% endfor %}";
+    let messages = vec![user("a"), user("b")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "10,0;11,1;");
+}
+
+#[test]
+fn loop_length_is_total_count() {
+    let template = "{% for message in messages %}{{ loop.index }}/{{ loop.length }} {% endfor %}";
+    let messages = vec![user("a"), user("b"), user("c")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "1/3 2/3 3/3 ");
+}
+
+#[test]
+fn loop_revindex_counts_down_to_one() {
+    let template = "{% for message in messages %}{{ loop.revindex }}{% endfor %}";
+    let messages = vec![user("a"), user("b"), user("c")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "321");
+}
+
+#[test]
+fn loop_revindex0_counts_down_to_zero() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{{ message['content'] }}{{ loop.revindex0 }}",
+        "{% if loop.revindex0 == 0 %}.{% else %},{% endif %}",
+        "{% endfor %}"
+    );
+    let messages = vec![user("a"), user("b"), user("c")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "a2,b1,c0.");
+}
+
+#[test]
+fn range_of_loop_length_in_nested_loop() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{% for i in range(loop.length) %}{{ i }}{% endfor %};",
+        "{% endfor %}"
+    );
+    let messages = vec![user("a"), assistant("b"), user("c")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "012;012;012;");
+}
+
+#[test]
+fn range_with_start_stop_and_step() {
+    let template = "{{ range(2, 5) | join(',') }}|{{ range(5, 0, -2) | join(',') }}|{{ range(0) | length }}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "2,3,4|5,3,1|0");
+}
+
+#[test]
+fn range_with_zero_step_is_error() {
+    let err = try_render_chat_template("{{ range(0, 3, 0) }}", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.to_string().contains("step must not be zero"), "{err}");
+}
+
+#[test]
+fn range_above_the_cap_is_error() {
+    let err = try_render_chat_template("{{ range(9223372036854775807) | length }}", &[], &RenderContext::new())
+        .unwrap_err();
+    assert!(err.to_string().contains("range() too big"), "{err}");
+    let err = try_render_chat_template("{{ range(0, 100001) | length }}", &[], &RenderContext::new()).unwrap_err();
+    assert!(err.to_string().contains("range() too big"), "{err}");
+    let rendered = render_chat_template_with_context("{{ range(100000) | length }}", &[], &RenderContext::new());
+    assert_eq!(rendered, "100000");
+}
+
+#[test]
+fn for_loop_unpacks_pairs() {
+    let template = "{% for k, v in [['a', '1'], ['b', '2']] %}{{ k }}={{ v }};{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "a=1;b=2;");
+}
+
+#[test]
+fn for_loop_unpacking_works_with_loop_filter() {
+    let template = "{% for name, n in [['x', 1], ['y', 2], ['z', 3]] if n != 2 %}{{ loop.index }}{{ name }}{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "1x2z");
+}
+
+#[test]
+fn for_loop_unpacking_length_mismatch_is_error() {
+    let template = "{% for a, b in [['only']] %}{{ a }}{% endfor %}";
+    let err = try_render_chat_template(template, &[], &RenderContext::new()).unwrap_err();
+    assert!(err.to_string().contains("Cannot unpack"), "{err}");
+}
+
+#[test]
+fn messages_indexed_before_during_and_after_loop() {
+    let template = concat!(
+        "{% if messages[0].role == 'system' %}[sys:{{ messages[0].content }}]{% endif %}",
+        "{% for message in messages %}",
+        "{{ message.content }}{% if message.content == messages[loop.index0].content %}={% endif %}",
+        "{% endfor %}",
+        "[last:{{ messages[-1].content }}][n:{{ messages | length }}][first:{{ messages[0]['content'] }}]"
+    );
+    let messages = vec![system("S"), user("U"), assistant("A")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "[sys:S]S=U=A=[last:A][n:3][first:S]");
+}
+
+#[test]
+fn messages_can_be_iterated_twice() {
+    let template = concat!(
+        "{% for message in messages %}{{ message.role }},{% endfor %}|",
+        "{% for message in messages %}{{ message.content }}{% endfor %}"
+    );
+    let messages = vec![system("S"), user("U")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "system,user,|SU");
+}
+
+#[test]
+fn for_else_renders_else_on_empty_messages() {
+    let template = "{% for message in messages %}{{ message.content }}{% else %}<default system prompt>{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[], &RenderContext::new());
+    assert_eq!(rendered, "<default system prompt>");
+}
+
+#[test]
+fn for_else_skips_else_when_items_exist() {
+    let template = "{% for message in messages %}{{ message.content }};{% else %}empty{% endfor %}";
+    let rendered = render_chat_template_with_context(template, &[user("a"), user("b")], &RenderContext::new());
+    assert_eq!(rendered, "a;b;");
+}
+
+#[test]
+fn for_else_runs_when_filter_removes_everything_or_iterable_is_missing() {
+    let template = concat!(
+        "{% for m in messages if m.role == 'tool' %}{{ m.content }}{% else %}no tools{% endfor %}|",
+        "{% for x in missing %}{{ x }}{% else %}none{% endfor %}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("a")], &RenderContext::new());
+    assert_eq!(rendered, "no tools|none");
+}
+
+#[test]
+fn user_loop_variable_is_shadowed_inside_for_and_restored_after() {
+    let template = concat!(
+        "{% set loop = 'mine' %}",
+        "{{ loop }}|",
+        "{% for message in messages %}{{ loop.index }}{% for x in [1, 2] %}{{ loop.index }}{% endfor %}{{ loop.index }},{% endfor %}",
+        "|{{ loop }}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("a"), user("b")], &RenderContext::new());
+    assert_eq!(rendered, "mine|1121,2122,|mine");
+}
+
+// ── Loop iterables ─────────────────────────────────────────────────────────
+
+#[test]
+fn loop_iterates_an_attribute() {
+    let template = concat!(
+        "{% set ns = namespace(parts=['a', 'b']) %}",
+        "{% for part in ns.parts %}{{ part }};{% endfor %}",
+        "{% for c in messages[-1]['content'] %}{{ c }}.{% endfor %}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("xy")], &RenderContext::new());
+    assert_eq!(rendered, "a;b;x.y.");
+}
+
+#[test]
+fn loop_iterates_a_sliced_list() {
+    let template = "{% for message in messages[1:] %}{{ message.content }}{% endfor %}|{% for message in messages[:-1] %}{{ message.content }}{% endfor %}";
+    let messages = vec![system("s"), user("a"), assistant("b")];
+    let rendered = render_chat_template_with_context(template, &messages, &RenderContext::new());
+    assert_eq!(rendered, "ab|sa");
+}
+
+// ── namespace() ────────────────────────────────────────────────────────────
+
+#[test]
+fn namespace_flag_set_inside_loop_survives_it() {
+    let template = concat!(
+        "{% set ns = namespace(found=false, count=0) %}",
+        "{% for message in messages %}",
+        "{% if message.role == 'system' %}{% set ns.found = true %}{% endif %}",
+        "{% set ns.count = ns.count + 1 %}",
+        "{% endfor %}",
+        "{{ ns.found }}:{{ ns.count }}"
+    );
+    let with_system = vec![user("a"), system("s"), assistant("b")];
+    let rendered = render_chat_template_with_context(template, &with_system, &RenderContext::new());
+    assert_eq!(rendered, "True:3");
+
+    let rendered = render_chat_template_with_context(template, &[user("a")], &RenderContext::new());
+    assert_eq!(rendered, "False:1");
+}
+
+#[test]
+fn plain_set_in_loop_does_not_leak_but_namespace_does() {
+    let template = concat!(
+        "{% set last = 'none' %}{% set ns = namespace(last='none') %}",
+        "{% for message in messages %}{% set last = message.role %}{% set ns.last = message.role %}{% endfor %}",
+        "{{ last }}|{{ ns.last }}"
+    );
+    let rendered = render_chat_template_with_context(template, &[user("a"), assistant("b")], &RenderContext::new());
+    assert_eq!(rendered, "none|assistant");
+}
+
+#[test]
+fn set_attribute_on_non_namespace_is_an_error() {
+    for template in ["{% set x = 'str' %}{% set x.y = 1 %}", "{% set missing.y = 1 %}"] {
+        let err = try_render_chat_template(template, &[], &RenderContext::new()).unwrap_err();
+        assert!(err.to_string().contains("Cannot assign to"), "{}: {}", template, err);
+    }
+}
+
+// ── Loop filters (`{% for x in xs if cond %}`) ────────────────────────────
+
+#[test]
+fn loop_filter_with_slice_skips_first_and_empty() {
+    let template = concat!(
+        "{% for message in messages[1:] if message.content %}",
+        "{{ loop.index }}:{{ message.content }}",
+        "{% if loop.first %}(first){% endif %}",
+        "{% if loop.last %}(last){% endif %};",
+        "{% endfor %}"
+    );
+    let messages = vec![
+        system("sys"),
+        user("a"),
+        assistant(""),
+        user("b"),
+        assistant(""),
+        user("c"),
+    ];
+    let rendered = render_chat_template_with_context(template, &messages, &ctx("", "", false));
+    assert_eq!(rendered, "1:a(first);2:b;3:c(last);");
+}
+
+#[test]
+fn loop_filter_keeps_only_user_messages_and_counts_them() {
+    let template = concat!(
+        "{% for message in messages if message.role == 'user' %}",
+        "{{ loop.index }}/{{ loop.length }}:{{ message.content }};",
+        "{% else %}none",
+        "{% endfor %}"
+    );
+    let messages = vec![system("s"), user("a"), assistant("x"), user("b")];
+    let rendered = render_chat_template_with_context(template, &messages, &ctx("", "", false));
+    assert_eq!(rendered, "1/2:a;2/2:b;");
+
+    let rendered = render_chat_template_with_context(template, &[system("s")], &ctx("", "", false));
+    assert_eq!(rendered, "none");
 }
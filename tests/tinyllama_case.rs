@@ -1,4 +1,7 @@
-use shimmyjinja::{render_chat_template, render_chat_template_with_context, ChatMessage, RenderContext};
+use shimmyjinja::{
+    render_chat_template, render_chat_template_with_context, render_info, render_segments,
+    ChatMessage, Origin, RenderContext,
+};
 
 #[test]
 fn test_tinyllama_template_full_features() {
@@ -19,14 +22,8 @@ fn test_tinyllama_template_full_features() {
     .trim();
 
     let messages = vec![
-        ChatMessage {
-            role: "system".to_string(),
-            content: "You are a friendly AI.".to_string(),
-        },
-        ChatMessage {
-            role: "user".to_string(),
-            content: "Hello!".to_string(),
-        },
+        ChatMessage::new("system", "You are a friendly AI."),
+        ChatMessage::new("user", "Hello!"),
     ];
 
     // Uses default context: eos_token="</s>", add_generation_prompt=true
@@ -54,14 +51,8 @@ fn test_tinyllama_with_explicit_context() {
     .trim();
 
     let messages = vec![
-        ChatMessage {
-            role: "system".to_string(),
-            content: "You are a friendly AI.".to_string(),
-        },
-        ChatMessage {
-            role: "user".to_string(),
-            content: "Hello!".to_string(),
-        },
+        ChatMessage::new("system", "You are a friendly AI."),
+        ChatMessage::new("user", "Hello!"),
     ];
 
     let mut ctx = RenderContext::new();
@@ -88,10 +79,7 @@ fn test_add_generation_prompt_false() {
     .trim();
 
     let messages = vec![
-        ChatMessage {
-            role: "user".to_string(),
-            content: "Hi".to_string(),
-        },
+        ChatMessage::new("user", "Hi"),
     ];
 
     let mut ctx = RenderContext::new();
@@ -113,10 +101,7 @@ fn test_custom_eos_token() {
     .trim();
 
     let messages = vec![
-        ChatMessage {
-            role: "user".to_string(),
-            content: "Hello".to_string(),
-        },
+        ChatMessage::new("user", "Hello"),
     ];
 
     let mut ctx = RenderContext::new();
@@ -146,10 +131,10 @@ fn test_multi_turn_conversation() {
     .trim();
 
     let messages = vec![
-        ChatMessage { role: "system".to_string(), content: "You help.".to_string() },
-        ChatMessage { role: "user".to_string(), content: "What is 2+2?".to_string() },
-        ChatMessage { role: "assistant".to_string(), content: "4".to_string() },
-        ChatMessage { role: "user".to_string(), content: "Thanks!".to_string() },
+        ChatMessage::new("system", "You help."),
+        ChatMessage::new("user", "What is 2+2?"),
+        ChatMessage::new("assistant", "4"),
+        ChatMessage::new("user", "Thanks!"),
     ];
 
     let mut ctx = RenderContext::new();
@@ -165,3 +150,236 @@ fn test_multi_turn_conversation() {
     // Should end with generation prompt since it's the last message
     assert!(rendered.trim().ends_with("<|assistant|>"), "generation prompt at end");
 }
+
+#[test]
+fn test_tinyllama_segments_carry_provenance() {
+    let template = r#"
+{% for message in messages %}
+{% if message['role'] == 'user' %}
+{{ '<|user|>\n' + message['content'] + eos_token }}
+{% elif message['role'] == 'system' %}
+{{ '<|system|>\n' + message['content'] + eos_token }}
+{% endif %}
+{% if loop.last and add_generation_prompt %}
+{{ '<|assistant|>' }}
+{% endif %}
+{% endfor %}
+"#
+    .trim();
+
+    let messages = vec![
+        ChatMessage::new("system", "You are a friendly AI."),
+        ChatMessage::new("user", "Hello!"),
+    ];
+    let mut ctx = RenderContext::new();
+    ctx.set_var("eos_token", "</s>");
+    ctx.set_flag("add_generation_prompt", true);
+
+    let segments = render_segments(template, &messages, &ctx).unwrap();
+    let joined: String = segments.iter().map(|s| s.text.as_str()).collect();
+    assert_eq!(joined, render_chat_template_with_context(template, &messages, &ctx));
+
+    let tagged: Vec<(Origin, &str)> = segments
+        .iter()
+        .filter(|s| s.origin != Origin::Literal)
+        .map(|s| (s.origin, s.text.as_str()))
+        .collect();
+    assert_eq!(
+        tagged,
+        vec![
+            (Origin::MessageContent, "You are a friendly AI."),
+            (Origin::SpecialToken, "</s>"),
+            (Origin::MessageContent, "Hello!"),
+            (Origin::SpecialToken, "</s>"),
+            (Origin::GenerationPrompt, "<|assistant|>\n"),
+        ]
+    );
+}
+
+#[test]
+fn test_long_concatenation_segments_match_plain_render() {
+    let operands: Vec<&str> = (0..200)
+        .map(|i| if i % 2 == 0 { "message['content']" } else { "eos_token" })
+        .collect();
+    let template = format!(
+        "{{% for message in messages %}}{{{{ {} }}}}{{% endfor %}}{{{{ 1 + 2 }}}}",
+        operands.join(" + ")
+    );
+    let messages = vec![ChatMessage::new("user", "Hi")];
+    let mut ctx = RenderContext::new();
+    ctx.set_var("eos_token", "</s>");
+
+    let segments = render_segments(&template, &messages, &ctx).unwrap();
+    let joined: String = segments.iter().map(|s| s.text.as_str()).collect();
+    assert_eq!(joined, format!("{}3", "Hi</s>".repeat(100)));
+    assert_eq!(joined, render_chat_template_with_context(&template, &messages, &ctx));
+    assert_eq!(segments.len(), 201);
+    assert_eq!(segments.last().map(|s| (s.origin, s.text.as_str())), Some((Origin::Literal, "3")));
+}
+
+#[test]
+fn test_generation_block_renders_transparently() {
+    let with_block = concat!(
+        "{% for message in messages %}",
+        "{% if message['role'] == 'assistant' %}",
+        "{{ '<|assistant|>\\n' }}{% generation %}{{ message['content'] + eos_token }}{% endgeneration %}",
+        "{% else %}",
+        "{{ '<|user|>\\n' + message['content'] + eos_token }}",
+        "{% endif %}",
+        "{% endfor %}"
+    );
+    let without_block = with_block
+        .replace("{% generation %}", "")
+        .replace("{% endgeneration %}", "");
+
+    let messages = vec![
+        ChatMessage::new("user", "Hi"),
+        ChatMessage::new("assistant", "Hello!"),
+    ];
+    let mut ctx = RenderContext::new();
+    ctx.set_var("eos_token", "</s>");
+
+    let plain = render_chat_template_with_context(with_block, &messages, &ctx);
+    assert_eq!(plain, "<|user|>\nHi</s><|assistant|>\nHello!</s>");
+    assert_eq!(plain, render_chat_template_with_context(&without_block, &messages, &ctx));
+
+    // Segment capture sees the same text
+    let captured: String = render_segments(with_block, &messages, &ctx)
+        .unwrap()
+        .into_iter()
+        .map(|s| s.text)
+        .collect();
+    assert_eq!(captured, plain);
+}
+
+#[test]
+fn test_filter_block_segments_match_filtered_output() {
+    let template = "<|system|>{% filter collapse_whitespace %}  {{ messages[0]['content'] }}\n\n  ok  {% endfilter %}</s>";
+    let messages = vec![
+        ChatMessage::new("system", "Be   brief."),
+    ];
+    let ctx = RenderContext::new();
+
+    let segments = render_segments(template, &messages, &ctx).unwrap();
+    let joined: String = segments.iter().map(|s| s.text.as_str()).collect();
+    assert_eq!(joined, "<|system|>Be brief. ok</s>");
+    assert_eq!(joined, render_chat_template_with_context(template, &messages, &ctx));
+}
+
+#[test]
+fn test_render_info_reports_generation_prompt() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{{ '<|user|>\\n' + message['content'] + eos_token }}",
+        "{% endfor %}",
+        "{% if add_generation_prompt %}{{ '<|assistant|>' }}{% endif %}"
+    );
+    let messages = vec![ChatMessage::new("user", "Hello!")];
+    let mut ctx = RenderContext::new();
+    ctx.set_var("eos_token", "</s>");
+
+    ctx.set_flag("add_generation_prompt", true);
+    let (output, info) = render_info(template, &messages, &ctx).unwrap();
+    assert_eq!(output, "<|user|>\nHello!</s><|assistant|>");
+    assert!(info.generation_prompt_emitted);
+
+    ctx.set_flag("add_generation_prompt", false);
+    let (output, info) = render_info(template, &messages, &ctx).unwrap();
+    assert_eq!(output, "<|user|>\nHello!</s>");
+    assert!(!info.generation_prompt_emitted);
+}
+
+#[test]
+fn test_render_info_when_template_ignores_flag() {
+    let template = "{% for message in messages %}{{ message['content'] }}{% endfor %}";
+    let messages = vec![ChatMessage::new("user", "Hello!")];
+    let mut ctx = RenderContext::new();
+    ctx.set_flag("add_generation_prompt", true);
+    let (_, info) = render_info(template, &messages, &ctx).unwrap();
+    assert!(!info.generation_prompt_emitted);
+}
+
+#[test]
+fn test_render_info_ignores_negated_generation_guards() {
+    let template = concat!(
+        "{% for message in messages %}{{ message['content'] }}",
+        "{% if loop.last and not add_generation_prompt %}</s>{% endif %}",
+        "{% endfor %}",
+        "{% if not add_generation_prompt %}EOS{% endif %}"
+    );
+    let messages = vec![ChatMessage::new("user", "Hello!")];
+    let mut ctx = RenderContext::new();
+
+    ctx.set_flag("add_generation_prompt", false);
+    let (output, info) = render_info(template, &messages, &ctx).unwrap();
+    assert_eq!(output, "Hello!</s>EOS");
+    assert!(!info.generation_prompt_emitted);
+
+    // A condition that holds regardless of the flag is not the generation prompt either.
+    ctx.set_flag("add_generation_prompt", true);
+    let (output, info) = render_info("{% if add_generation_prompt or true %}x{% endif %}", &messages, &ctx).unwrap();
+    assert_eq!(output, "x");
+    assert!(!info.generation_prompt_emitted);
+}
+
+#[test]
+fn test_render_info_marks_else_opened_by_generation_flag() {
+    let template = "{% if not add_generation_prompt %}EOS{% else %}<|assistant|>{% endif %}";
+    let mut ctx = RenderContext::new();
+
+    ctx.set_flag("add_generation_prompt", true);
+    let (output, info) = render_info(template, &[], &ctx).unwrap();
+    assert_eq!(output, "<|assistant|>");
+    assert!(info.generation_prompt_emitted);
+
+    ctx.set_flag("add_generation_prompt", false);
+    let (output, info) = render_info(template, &[], &ctx).unwrap();
+    assert_eq!(output, "EOS");
+    assert!(!info.generation_prompt_emitted);
+
+    // An else that is taken regardless of the flag is not the generation prompt.
+    ctx.set_flag("add_generation_prompt", true);
+    let (output, info) = render_info("{% if false %}a{% else %}b{% endif %}", &[], &ctx).unwrap();
+    assert_eq!(output, "b");
+    assert!(!info.generation_prompt_emitted);
+}
+
+#[test]
+fn visitor_counts_var_nodes_in_tinyllama_template() {
+    use shimmyjinja::ast::{walk_expr, walk_node, walk_template, Expr, Node, NodeVisitor};
+    use shimmyjinja::parser::Parser;
+
+    #[derive(Default)]
+    struct VarCounter {
+        outputs: usize,
+        eos_reads: usize,
+    }
+
+    impl NodeVisitor for VarCounter {
+        fn visit_node(&mut self, node: &Node) {
+            if let Node::Var(_) = node {
+                self.outputs += 1;
+            }
+            walk_node(self, node);
+        }
+
+        fn visit_expr(&mut self, expr: &Expr) {
+            if matches!(expr, Expr::Var(name) if name == "eos_token") {
+                self.eos_reads += 1;
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    let template = "{% for message in messages %}\
+{% if message['role'] == 'user' %}{{ '<|user|>\\n' + message['content'] + eos_token }}\
+{% elif message['role'] == 'system' %}{{ '<|system|>\\n' + message['content'] + eos_token }}\
+{% elif message['role'] == 'assistant' %}{{ '<|assistant|>\\n' + message['content'] + eos_token }}\
+{% endif %}{% if loop.last and add_generation_prompt %}{{ '<|assistant|>' }}{% endif %}{% endfor %}";
+    let nodes = Parser::new(template).parse().unwrap();
+
+    let mut counter = VarCounter::default();
+    walk_template(&nodes, &mut counter);
+    assert_eq!(counter.outputs, 4);
+    assert_eq!(counter.eos_reads, 3);
+}
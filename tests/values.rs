@@ -0,0 +1,542 @@
+//! Tests for non-string `Value`s flowing through templates via
+//! [`Template::render_values`].
+
+use shimmyjinja::{ChatMessage, Content, RenderContext, Template, Value};
+use std::collections::BTreeMap;
+
+// ── helpers ────────────────────────────────────────────────────────────────
+
+fn message(role: &str, content: Value) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("role".to_string(), Value::String(role.to_string()));
+    map.insert("content".to_string(), content);
+    Value::Map(map)
+}
+
+fn render(template: &str, messages: Vec<Value>) -> String {
+    Template::compile(template)
+        .unwrap()
+        .render_values(messages, &RenderContext::new())
+        .unwrap()
+}
+
+const CONTENT_TMPL: &str = "{% for message in messages %}{{ message.role }}={{ message.content }};{% endfor %}";
+
+// ── content coercion ───────────────────────────────────────────────────────
+
+#[test]
+fn int_content_renders_as_decimal() {
+    let out = render(CONTENT_TMPL, vec![message("user", Value::Int(42))]);
+    assert_eq!(out, "user=42;");
+}
+
+#[test]
+fn bool_content_renders_python_style() {
+    let out = render(CONTENT_TMPL, vec![message("user", Value::Bool(true)), message("assistant", Value::Bool(false))]);
+    assert_eq!(out, "user=True;assistant=False;");
+}
+
+#[test]
+fn array_content_renders_as_json() {
+    let content = Value::Array(vec![Value::String("a".into()), Value::Int(1), Value::Null]);
+    let out = render(CONTENT_TMPL, vec![message("user", content)]);
+    assert_eq!(out, r#"user=["a", 1, null];"#);
+}
+
+#[test]
+fn map_content_renders_as_json_with_sorted_keys() {
+    let mut part = BTreeMap::new();
+    part.insert("type".to_string(), Value::String("text".into()));
+    part.insert("text".to_string(), Value::String("say \"hi\"".into()));
+    let out = render(CONTENT_TMPL, vec![message("user", Value::Map(part))]);
+    assert_eq!(out, r#"user={"text": "say \"hi\"", "type": "text"};"#);
+}
+
+// ── container equality ─────────────────────────────────────────────────────
+
+const EQ_TMPL: &str = "{{ messages[0].content == messages[1].content }}";
+
+fn ints(ns: &[i64]) -> Value {
+    Value::Array(ns.iter().map(|n| Value::Int(*n)).collect())
+}
+
+#[test]
+fn equal_arrays_compare_equal() {
+    let out = render(EQ_TMPL, vec![message("user", ints(&[1, 2])), message("user", ints(&[1, 2]))]);
+    assert_eq!(out, "True");
+}
+
+#[test]
+fn array_equality_is_order_sensitive() {
+    let out = render(EQ_TMPL, vec![message("user", ints(&[1, 2])), message("user", ints(&[2, 1]))]);
+    assert_eq!(out, "False");
+}
+
+#[test]
+fn maps_with_same_entries_compare_equal() {
+    // Insert in opposite orders — map equality must not depend on it.
+    let mut a = BTreeMap::new();
+    a.insert("type".to_string(), Value::String("text".into()));
+    a.insert("text".to_string(), Value::String("hi".into()));
+    let mut b = BTreeMap::new();
+    b.insert("text".to_string(), Value::String("hi".into()));
+    b.insert("type".to_string(), Value::String("text".into()));
+    let out = render(EQ_TMPL, vec![message("user", Value::Map(a)), message("user", Value::Map(b))]);
+    assert_eq!(out, "True");
+}
+
+#[test]
+fn maps_with_different_values_compare_unequal() {
+    let mut a = BTreeMap::new();
+    a.insert("text".to_string(), Value::String("hi".into()));
+    let mut b = BTreeMap::new();
+    b.insert("text".to_string(), Value::String("bye".into()));
+    let out = render(EQ_TMPL, vec![message("user", Value::Map(a)), message("user", Value::Map(b))]);
+    assert_eq!(out, "False");
+}
+
+// ── membership ─────────────────────────────────────────────────────────────
+
+fn strs(items: &[&str]) -> Value {
+    Value::Array(items.iter().map(|s| Value::String(s.to_string())).collect())
+}
+
+#[test]
+fn in_operator_finds_array_member() {
+    let template = "{% for message in messages %}{% if 'image' in message.content %}yes{% else %}no{% endif %}{% endfor %}";
+    let out = render(template, vec![message("user", strs(&["text", "image"])), message("user", strs(&["text"]))]);
+    assert_eq!(out, "yesno");
+}
+
+#[test]
+fn not_in_operator_on_array() {
+    let template = "{% for message in messages %}{{ 'audio' not in message.content }}{% endfor %}";
+    let out = render(template, vec![message("user", strs(&["text", "audio"])), message("user", strs(&[]))]);
+    assert_eq!(out, "FalseTrue");
+}
+
+#[test]
+fn in_operator_substring_on_string() {
+    let template = "{% for message in messages %}{{ 'lo w' in message.content }}{{ 'xyz' in message.content }}{% endfor %}";
+    let out = render(template, vec![message("user", Value::String("hello world".into()))]);
+    assert_eq!(out, "TrueFalse");
+}
+
+// ── tojson ─────────────────────────────────────────────────────────────────
+
+const TOJSON_TMPL: &str = "{{ messages[0].content | tojson }}";
+
+#[test]
+fn tojson_nested_map_compact() {
+    let mut function = BTreeMap::new();
+    function.insert("name".to_string(), Value::String("get_weather".into()));
+    function.insert("args".to_string(), strs(&["city", "unit"]));
+    let mut tool = BTreeMap::new();
+    tool.insert("type".to_string(), Value::String("function".into()));
+    tool.insert("function".to_string(), Value::Map(function));
+    let out = render(TOJSON_TMPL, vec![message("user", Value::Map(tool))]);
+    assert_eq!(out, r#"{"function": {"args": ["city", "unit"], "name": "get_weather"}, "type": "function"}"#);
+}
+
+#[test]
+fn tojson_escapes_special_characters() {
+    let content = Value::String("say \"hi\"\\\n\t\u{1}".into());
+    let out = render(TOJSON_TMPL, vec![message("user", content)]);
+    assert_eq!(out, r#""say \"hi\"\\\n\t\u0001""#);
+}
+
+#[test]
+fn tojson_scalars() {
+    let content = Value::Array(vec![Value::Int(-3), Value::Bool(true), Value::Null]);
+    let out = render(TOJSON_TMPL, vec![message("user", content)]);
+    assert_eq!(out, "[-3, true, null]");
+}
+
+#[test]
+fn tojson_key_order_is_stable_across_renders() {
+    let keys = ["zeta", "alpha", "mid", "beta", "omega", "gamma", "delta", "eta"];
+    let template = Template::compile("{{ messages[0].content | tojson }}|{{ messages[0].content.keys() | join(',') }}").unwrap();
+    let expected = concat!(
+        r#"{"alpha": 1, "beta": 3, "delta": 6, "eta": 7, "gamma": 5, "mid": 2, "omega": 4, "zeta": 0}"#,
+        "|alpha,beta,delta,eta,gamma,mid,omega,zeta"
+    );
+    for rotation in 0..keys.len() {
+        // Insert in a different order each time; the output must not change.
+        let mut map = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate().cycle().skip(rotation).take(keys.len()) {
+            map.insert(key.to_string(), Value::Int(i as i64));
+        }
+        let out = template.render_values(vec![message("user", Value::Map(map))], &RenderContext::new()).unwrap();
+        assert_eq!(out, expected);
+    }
+    let kwargs = "{{ namespace(zeta=0, alpha=1, mid=2) | tojson }}";
+    assert_eq!(render(kwargs, Vec::new()), r#"{"alpha": 1, "mid": 2, "zeta": 0}"#);
+}
+
+#[test]
+fn tojson_with_indent_pretty_prints() {
+    let mut map = BTreeMap::new();
+    map.insert("a".to_string(), ints(&[1, 2]));
+    map.insert("b".to_string(), Value::Map(BTreeMap::new()));
+    let out = render("{{ messages[0].content | tojson(indent=2) }}", vec![message("user", Value::Map(map))]);
+    assert_eq!(out, "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": {}\n}");
+}
+
+#[test]
+fn tojson_rejects_negative_indent() {
+    let err = Template::compile("{{ messages[0].content | tojson(-1) }}")
+        .unwrap()
+        .render_values(vec![message("user", Value::Null)], &RenderContext::new())
+        .unwrap_err();
+    assert!(err.to_string().contains("tojson indent"), "{}", err);
+}
+
+// ── multimodal content ─────────────────────────────────────────────────────
+
+fn part(kind: &str, text: Option<&str>) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("type".to_string(), Value::String(kind.to_string()));
+    if let Some(text) = text {
+        map.insert("text".to_string(), Value::String(text.to_string()));
+    }
+    Value::Map(map)
+}
+
+const PARTS_TMPL: &str = concat!(
+    "{% for message in messages %}",
+    "{{ message.role }}:",
+    "{% if message.content is string %}{{ message.content }}",
+    "{% else %}{% for part in message.content %}",
+    "{% if part.type == 'image' %}<image>{% elif part.type == 'text' %}{{ part.text }}{% endif %}",
+    "{% endfor %}{% endif %};",
+    "{% endfor %}"
+);
+
+#[test]
+fn content_parts_iterate_as_list() {
+    let messages = vec![
+        ChatMessage::new("system", "Describe images."),
+        ChatMessage::new("user", Content::Parts(vec![part("image", None), part("text", Some("What is this?"))])),
+    ];
+    let out = Template::compile(PARTS_TMPL).unwrap().render(&messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "system:Describe images.;user:<image>What is this?;");
+}
+
+#[test]
+fn content_text_and_parts_conversions() {
+    assert_eq!(Content::from("hi"), Content::Text("hi".to_string()));
+    assert_eq!(Content::from("hi").as_text(), Some("hi"));
+    assert_eq!(Content::from(vec![part("image", None)]).as_text(), None);
+    assert_eq!(Content::default(), Content::Text(String::new()));
+}
+
+// ── integer rendering and overflow ─────────────────────────────────────────
+
+const INT_TMPL: &str = "{{ messages[0].content }}";
+
+#[test]
+fn ints_render_as_plain_decimal() {
+    for (n, text) in [(-1, "-1"), (0, "0"), (i64::MAX, "9223372036854775807"), (i64::MIN, "-9223372036854775808")] {
+        assert_eq!(render(INT_TMPL, vec![message("user", Value::Int(n))]), text);
+    }
+}
+
+fn render_err(template: &str, messages: Vec<Value>) -> String {
+    Template::compile(template)
+        .unwrap()
+        .render_values(messages, &RenderContext::new())
+        .unwrap_err()
+        .to_string()
+}
+
+#[test]
+fn addition_overflow_is_error() {
+    let err = render_err("{{ messages[0].content + 1 }}", vec![message("user", Value::Int(i64::MAX))]);
+    assert!(err.contains("Integer overflow: 9223372036854775807 + 1"), "{err}");
+}
+
+#[test]
+fn subtraction_and_modulo_overflow_are_errors() {
+    let err = render_err("{{ messages[0].content - 1 }}", vec![message("user", Value::Int(i64::MIN))]);
+    assert!(err.contains("Integer overflow"), "{err}");
+    let err = render_err("{{ messages[0].content % -1 }}", vec![message("user", Value::Int(i64::MIN))]);
+    assert!(err.contains("Integer overflow"), "{err}");
+}
+
+#[test]
+fn arithmetic_at_the_bounds_still_works() {
+    assert_eq!(render("{{ messages[0].content - 1 }}", vec![message("user", Value::Int(i64::MAX))]), "9223372036854775806");
+    assert_eq!(render("{{ messages[0].content + 1 }}", vec![message("user", Value::Int(i64::MIN))]), "-9223372036854775807");
+}
+
+// ── extra message fields ───────────────────────────────────────────────────
+
+const NAME_TMPL: &str = "{% for message in messages %}{{ message.role }}({{ message.name }})={{ message.content }};{% endfor %}";
+
+#[test]
+fn extra_fields_resolve_as_message_attributes() {
+    let messages = vec![
+        ChatMessage::new("user", "hi").with_extra("name", Value::String("alice".into())),
+        ChatMessage::new("tool", "42").with_extra("tool_call_id", Value::String("call_1".into())),
+    ];
+    let out = Template::compile(NAME_TMPL).unwrap().render(&messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "user(alice)=hi;tool()=42;");
+
+    let id = Template::compile("{{ messages[1]['tool_call_id'] }}").unwrap().render(&messages, &RenderContext::new());
+    assert_eq!(id.unwrap(), "call_1");
+}
+
+#[test]
+fn extra_fields_do_not_override_role_or_content() {
+    let messages = vec![ChatMessage::new("user", "real")
+        .with_extra("role", Value::String("system".into()))
+        .with_extra("content", Value::String("fake".into()))];
+    let out = Template::compile(NAME_TMPL).unwrap().render(&messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "user()=real;");
+}
+
+// ── tool calls ─────────────────────────────────────────────────────────────
+
+fn tool_call(name: &str, arguments: Value) -> Value {
+    let mut function = BTreeMap::new();
+    function.insert("name".to_string(), Value::String(name.to_string()));
+    function.insert("arguments".to_string(), arguments);
+    let mut call = BTreeMap::new();
+    call.insert("type".to_string(), Value::String("function".into()));
+    call.insert("function".to_string(), Value::Map(function));
+    Value::Map(call)
+}
+
+const TOOL_CALLS_TMPL: &str = concat!(
+    "{% for message in messages %}",
+    "{% if message.tool_calls is defined %}",
+    "{% for tool_call in message.tool_calls %}",
+    "<call>{{ tool_call.function.name }}({{ tool_call.function.arguments | tojson }})</call>",
+    "{% endfor %}",
+    "{% else %}{{ message.role }}: {{ message.content }}\n{% endif %}",
+    "{% endfor %}"
+);
+
+#[test]
+fn tool_calls_render_name_and_arguments() {
+    let mut args = BTreeMap::new();
+    args.insert("city".to_string(), Value::String("Paris".into()));
+    args.insert("days".to_string(), Value::Int(3));
+    let messages = vec![
+        ChatMessage::new("user", "Weather in Paris?"),
+        ChatMessage::new("assistant", "").with_tool_calls(vec![
+            tool_call("get_weather", Value::Map(args)),
+            tool_call("get_time", Value::Map(BTreeMap::new())),
+        ]),
+    ];
+    let out = Template::compile(TOOL_CALLS_TMPL).unwrap().render(&messages, &RenderContext::new()).unwrap();
+    assert_eq!(
+        out,
+        "user: Weather in Paris?\n<call>get_weather({\"city\": \"Paris\", \"days\": 3})</call><call>get_time({})</call>"
+    );
+}
+
+#[test]
+fn bare_attribute_chain_is_falsy_when_tool_calls_missing() {
+    let template = concat!(
+        "{% for message in messages %}",
+        "{% if message.tool_calls %}calls{% elif message.tool_calls.function.name %}deep{% else %}{{ message.content }}{% endif %};",
+        "{% endfor %}"
+    );
+    let messages = vec![
+        ChatMessage::new("user", "hi"),
+        ChatMessage::new("assistant", "").with_tool_calls(vec![tool_call("get_time", Value::Map(BTreeMap::new()))]),
+    ];
+    let out = Template::compile(template).unwrap().render(&messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "hi;calls;");
+}
+
+#[test]
+fn messages_without_tool_calls_leave_field_undefined() {
+    let messages = vec![ChatMessage::new("assistant", "plain")];
+    let out = Template::compile("{{ messages[0].tool_calls is defined }}|{{ messages[0].tool_calls | length }}")
+        .unwrap()
+        .render(&messages, &RenderContext::new())
+        .unwrap();
+    assert_eq!(out, "False|0");
+}
+
+#[test]
+fn selectattr_truthiness_form_keeps_messages_with_tool_calls() {
+    let messages = vec![
+        ChatMessage::new("user", "Weather?"),
+        ChatMessage::new("assistant", "").with_tool_calls(vec![tool_call("get_weather", Value::Map(BTreeMap::new()))]),
+        ChatMessage::new("assistant", "Sunny."),
+    ];
+    let template = "{% for m in messages | selectattr('tool_calls') %}{{ m.tool_calls[0].function.name }}{% endfor %}";
+    let out = Template::compile(template).unwrap().render(&messages, &RenderContext::new()).unwrap();
+    assert_eq!(out, "get_weather");
+}
+
+// ── structured context values ──────────────────────────────────────────────
+
+fn tool_schema(name: &str, description: &str) -> Value {
+    let mut function = BTreeMap::new();
+    function.insert("name".to_string(), Value::String(name.to_string()));
+    function.insert("description".to_string(), Value::String(description.to_string()));
+    let mut tool = BTreeMap::new();
+    tool.insert("type".to_string(), Value::String("function".into()));
+    tool.insert("function".to_string(), Value::Map(function));
+    Value::Map(tool)
+}
+
+const TOOLS_TMPL: &str = concat!(
+    "{% if tools %}# Tools ({{ tools | length }})\n",
+    "{% for tool in tools %}- {{ tool.function.name }}: {{ tool.function.description }}\n{% endfor %}",
+    "{% endif %}",
+    "{% for message in messages %}{{ message.content }}{% endfor %}"
+);
+
+#[test]
+fn tools_list_injected_via_set_value() {
+    let mut ctx = RenderContext::new();
+    ctx.set_value(
+        "tools",
+        Value::Array(vec![tool_schema("get_weather", "Current weather"), tool_schema("get_time", "Local time")]),
+    );
+    let out = Template::compile(TOOLS_TMPL).unwrap().render(&[ChatMessage::new("user", "hi")], &ctx).unwrap();
+    assert_eq!(out, "# Tools (2)\n- get_weather: Current weather\n- get_time: Local time\nhi");
+}
+
+#[test]
+fn tools_read_inside_message_loop_render_each_time() {
+    // Lookups borrow through `tools` and the loop item; output must match
+    // across every path form and stay unaffected by missing keys and indices.
+    let mut ctx = RenderContext::new();
+    ctx.set_value(
+        "tools",
+        Value::Array(vec![tool_schema("get_weather", "Current weather"), tool_schema("get_time", "Local time")]),
+    );
+    let template = concat!(
+        "{% for message in messages %}",
+        "{{ loop.index }}:{{ tools[0].function.name }},{{ tools[-1]['function']['name'] }},",
+        "{{ tools[loop.index0].function.description }},{{ tools[5] is defined }},",
+        "{{ tools[0].function.missing is none }},{{ message['content'] }};",
+        "{% endfor %}"
+    );
+    let messages = vec![ChatMessage::new("user", "a"), ChatMessage::new("assistant", "b")];
+    let out = Template::compile(template).unwrap().render(&messages, &ctx).unwrap();
+    assert_eq!(
+        out,
+        "1:get_weather,get_time,Current weather,False,True,a;2:get_weather,get_time,Local time,False,True,b;"
+    );
+}
+
+#[test]
+fn tools_section_skipped_when_unset() {
+    let mut ctx = RenderContext::new();
+    ctx.set_value("tools", Value::Array(Vec::new()));
+    let template = Template::compile(TOOLS_TMPL).unwrap();
+    assert_eq!(template.render(&[ChatMessage::new("user", "hi")], &ctx).unwrap(), "hi");
+    ctx.unset("tools");
+    assert_eq!(template.render(&[ChatMessage::new("user", "hi")], &ctx).unwrap(), "hi");
+}
+
+#[test]
+fn nested_map_value_round_trips_through_context() {
+    let mut limits = BTreeMap::new();
+    limits.insert("max_tokens".to_string(), Value::Int(512));
+    let mut config = BTreeMap::new();
+    config.insert("model".to_string(), Value::String("tiny".into()));
+    config.insert("limits".to_string(), Value::Map(limits));
+
+    let mut ctx = RenderContext::new();
+    ctx.set_value("config", Value::Map(config));
+    let out = Template::compile("{{ config.model }}:{{ config.limits.max_tokens }}:{{ config['limits']['max_tokens'] + 1 }}")
+        .unwrap()
+        .render(&[], &ctx)
+        .unwrap();
+    assert_eq!(out, "tiny:512:513");
+}
+
+// ── is defined ─────────────────────────────────────────────────────────────
+
+const DEFINED_TMPL: &str = "{% if tools is defined %}tools{% else %}none{% endif %}|{{ tools is not defined }}|{{ tools is none }}";
+
+#[test]
+fn defined_test_on_present_and_missing_variables() {
+    let template = Template::compile(DEFINED_TMPL).unwrap();
+    assert_eq!(template.render(&[], &RenderContext::new()).unwrap(), "none|True|True");
+
+    let mut ctx = RenderContext::new();
+    ctx.set_value("tools", Value::Array(vec![tool_schema("get_time", "Local time")]));
+    assert_eq!(template.render(&[], &ctx).unwrap(), "tools|False|False");
+}
+
+#[test]
+fn explicit_null_is_defined_but_none() {
+    let mut ctx = RenderContext::new();
+    ctx.set_value("tools", Value::Null);
+    let out = Template::compile(DEFINED_TMPL).unwrap().render(&[], &ctx).unwrap();
+    assert_eq!(out, "tools|False|True");
+
+    let msg = ChatMessage::new("user", "hi").with_extra("name", Value::Null);
+    let out = Template::compile("{{ messages[0].name is defined }}{{ messages[0]['name'] is defined }}{{ messages[0].age is defined }}")
+        .unwrap()
+        .render(&[msg], &RenderContext::new())
+        .unwrap();
+    assert_eq!(out, "TrueTrueFalse");
+}
+
+// ── dict methods ───────────────────────────────────────────────────────────
+
+fn sampling() -> RenderContext {
+    let mut params = BTreeMap::new();
+    params.insert("top_p".to_string(), Value::String("0.9".into()));
+    params.insert("max_tokens".to_string(), Value::Int(256));
+    params.insert("stream".to_string(), Value::Bool(false));
+    let mut ctx = RenderContext::new();
+    ctx.set_value("extra", Value::Map(params));
+    ctx
+}
+
+#[test]
+fn items_unpack_into_key_value_pairs_in_sorted_order() {
+    let template = "{% for k, v in extra.items() %}{{ k }}={{ v }};{% endfor %}";
+    let out = Template::compile(template).unwrap().render(&[], &sampling()).unwrap();
+    assert_eq!(out, "max_tokens=256;stream=False;top_p=0.9;");
+}
+
+#[test]
+fn keys_and_values_follow_the_same_order() {
+    let template = "{{ extra.keys() | join(',') }}|{{ extra.values() | join(',') }}|{{ missing.items() | length }}";
+    let out = Template::compile(template).unwrap().render(&[], &sampling()).unwrap();
+    assert_eq!(out, "max_tokens,stream,top_p|256,False,0.9|0");
+}
+
+// ── numeric string coercion ────────────────────────────────────────────────
+
+const MAX_TURNS_TMPL: &str =
+    "{% for message in messages %}{{ message.content }}{% if loop.index == max_turns %}|{% endif %}{% endfor %}";
+
+fn six_turns() -> Vec<ChatMessage> {
+    (1..=6).map(|i| ChatMessage::new("user", i.to_string())).collect()
+}
+
+#[test]
+fn numeric_string_compares_as_int_when_coercing() {
+    let mut ctx = RenderContext::new();
+    ctx.set_var("max_turns", "5").set_coerce_numeric_strings(true);
+    let template = Template::compile(MAX_TURNS_TMPL).unwrap();
+    assert_eq!(template.render(&six_turns(), &ctx).unwrap(), "12345|6");
+
+    let ordering = "{{ 4 < max_turns }}{{ max_turns >= 6 }}{{ max_turns != 5 }}{{ 'five' == 5 }}";
+    let out = Template::compile(ordering).unwrap().render(&[], &ctx).unwrap();
+    assert_eq!(out, "TrueFalseFalseFalse");
+}
+
+#[test]
+fn numeric_string_stays_a_string_by_default() {
+    let mut ctx = RenderContext::new();
+    ctx.set_var("max_turns", "5");
+    let template = Template::compile(MAX_TURNS_TMPL).unwrap();
+    assert_eq!(template.render(&six_turns(), &ctx).unwrap(), "123456");
+
+    let err = Template::compile("{{ 4 < max_turns }}").unwrap().render(&[], &ctx).unwrap_err();
+    assert!(err.to_string().contains("Cannot order-compare"), "{}", err);
+}